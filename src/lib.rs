@@ -0,0 +1,9 @@
+//! The packed format as a library, so it can be built and tested without the sandbox binary and
+//! without `std`.
+
+#![no_std]
+
+#[cfg(feature = "std")]
+extern crate std;
+
+pub mod packed;
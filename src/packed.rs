@@ -1,41 +1,208 @@
+//! A compact binary format for serde.
+//!
+//! Everything that works on slices only needs `core`. The `alloc` feature adds the APIs that hand
+//! out `Vec` and `String` and the struct encodings that buffer their fields, and the `std` feature,
+//! on by default, adds the ones that work on [`std::io`] streams.
+
+#[cfg(feature = "alloc")]
+extern crate alloc;
+
 use serde::Serialize;
-use serde::ser::Error as _;
+use serde::Deserialize;
+use serde::de::IntoDeserializer;
+#[cfg(feature = "alloc")]
+use alloc::{string::{String, ToString}, vec, vec::Vec};
+#[cfg(feature = "std")]
+use std::io::Read as _;
+
+mod checksum;
+#[cfg(feature = "alloc")]
+mod envelope;
+#[cfg(feature = "std")]
+pub mod framing;
+pub mod key;
+#[cfg(feature = "alloc")]
+pub mod schema;
+#[cfg(feature = "alloc")]
+mod value;
+
+pub use checksum::crc32c;
+#[cfg(feature = "alloc")]
+pub use envelope::{fingerprint, from_bytes_enveloped, to_bytes_enveloped, FORMAT_VERSION, MAGIC};
+#[cfg(feature = "alloc")]
+pub use value::{from_value, to_value, Value};
+
+/// The broad category of an [`Error`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ErrorKind {
+    /// The input ended in the middle of a value.
+    UnexpectedEof,
+    /// A marker byte or variant index is not one the format defines.
+    InvalidTag,
+    /// A string or char is not valid UTF-8.
+    InvalidUtf8,
+    /// A length does not fit the width used to encode it.
+    LengthOverflow,
+    /// The value or the request is outside what the format supports.
+    Unsupported,
+    /// Input remains after the top-level value.
+    TrailingData,
+    /// The underlying reader or writer failed.
+    Io,
+    /// A variable-length integer is malformed or does not fit the type being read.
+    IntegerOverflow,
+    /// The input asks for more than the configured limits allow.
+    LimitExceeded,
+    /// An enveloped message does not start with the magic bytes.
+    BadMagic,
+    /// An enveloped message was written by a different revision of the format.
+    VersionMismatch,
+    /// An enveloped message was written with different layout settings.
+    ConfigMismatch,
+    /// An enveloped message holds a different type than the one being decoded.
+    TypeMismatch,
+    /// A checksum trailer does not match the bytes it covers.
+    ChecksumMismatch,
+    /// The value does not fit into the buffer given to `to_slice`.
+    BufferFull,
+    /// A map with duplicate keys was written in canonical mode, or canonical input is not in its
+    /// one canonical form.
+    NonCanonical,
+    /// Raised through `serde::ser::Error::custom` or `serde::de::Error::custom`.
+    Custom,
+}
 
+/// One step on the way from the top-level value to where an error happened.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PathSegment {
+    /// A named struct field.
+    Field(&'static str),
+    /// A sequence element, tuple element or map entry.
+    Index(usize),
+}
 
+/// Without the `alloc` feature only the kind and the offset are kept, the message and the path
+/// are dropped.
 #[derive(Debug)]
 pub struct Error {
+    kind: ErrorKind,
+    #[cfg(feature = "alloc")]
     message: String,
+    offset: Option<u64>,
+    /// Innermost segment first, segments are appended as the error bubbles up.
+    #[cfg(feature = "alloc")]
+    path: Vec<PathSegment>,
+    #[cfg(feature = "std")]
+    source: Option<std::io::Error>,
+}
+
+impl Error
+{
+    #[cfg_attr(not(feature = "alloc"), allow(unused_variables))]
+    pub fn new<T: core::fmt::Display>(kind: ErrorKind, msg: T) -> Self {
+        Self {
+            kind,
+            #[cfg(feature = "alloc")]
+            message: msg.to_string(),
+            offset: None,
+            #[cfg(feature = "alloc")]
+            path: Vec::new(),
+            #[cfg(feature = "std")]
+            source: None,
+        }
+    }
+
+    #[cfg(feature = "std")]
+    fn io(err: std::io::Error) -> Self {
+        let kind = match err.kind() {
+            std::io::ErrorKind::UnexpectedEof => ErrorKind::UnexpectedEof,
+            _ => ErrorKind::Io,
+        };
+        Self {
+            source: Some(err),
+            ..Self::new(kind, "i/o error")
+        }
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+
+    /// Byte offset into the input where decoding failed, if the error came from a deserializer.
+    pub fn offset(&self) -> Option<u64> {
+        self.offset
+    }
+
+    /// The struct fields and element indices leading to the failure, outermost first.
+    pub fn path(&self) -> impl Iterator<Item = &PathSegment> {
+        #[cfg(feature = "alloc")]
+        return self.path.iter().rev();
+        #[cfg(not(feature = "alloc"))]
+        return [].iter();
+    }
+
+    /// Record the offset unless a more precise one was recorded further down.
+    fn at(mut self, offset: u64) -> Self {
+        self.offset.get_or_insert(offset);
+        self
+    }
+
+    #[cfg_attr(not(feature = "alloc"), allow(unused_mut, unused_variables))]
+    fn within(mut self, segment: PathSegment) -> Self {
+        #[cfg(feature = "alloc")]
+        self.path.push(segment);
+        self
+    }
 }
 
-impl std::fmt::Display for Error
+impl core::fmt::Display for Error
 {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "{}", self.message)
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        #[cfg(feature = "alloc")]
+        write!(f, "{}", self.message)?;
+        #[cfg(not(feature = "alloc"))]
+        write!(f, "{:?}", self.kind)?;
+        #[cfg(feature = "std")]
+        if let Some(source) = &self.source {
+            write!(f, ": {}", source)?;
+        }
+        if self.path().next().is_some() {
+            write!(f, " at ")?;
+            for (i, segment) in self.path().enumerate() {
+                match segment {
+                    PathSegment::Field(name) if i == 0 => write!(f, "{}", name)?,
+                    PathSegment::Field(name) => write!(f, ".{}", name)?,
+                    PathSegment::Index(index) => write!(f, "[{}]", index)?,
+                }
+            }
+        }
+        if let Some(offset) = self.offset {
+            write!(f, " (byte offset {})", offset)?;
+        }
+        Ok(())
     }
 }
 
-impl std::error::Error for Error
+impl core::error::Error for Error
 {
-    fn description(&self) -> &str {
-        self.message.as_str()
+    #[cfg(feature = "std")]
+    fn source(&self) -> Option<&(dyn core::error::Error + 'static)> {
+        self.source.as_ref().map(|err| err as _)
     }
 }
 
 impl serde::ser::Error for Error
 {
-    fn custom<T: std::fmt::Display>(msg: T) -> Self {
-        Self {
-            message: msg.to_string(),
-        }
+    fn custom<T: core::fmt::Display>(msg: T) -> Self {
+        Self::new(ErrorKind::Custom, msg)
     }
 }
 
 impl serde::de::Error for Error
 {
-    fn custom<T: std::fmt::Display>(msg: T) -> Self {
-        Self {
-            message: msg.to_string(),
-        }
+    fn custom<T: core::fmt::Display>(msg: T) -> Self {
+        Self::new(ErrorKind::Custom, msg)
     }
 }
 
@@ -44,210 +211,1077 @@ const FALSE: u8 = 0xf4;
 const NONE: u8 = 0xf6;
 const SOME: u8 = 0xf7;
 
-pub fn to_bytes<T: Serialize>(value: &T) -> Result<Vec<u8>, Error> {
-    let mut serializer = Serializer::new();
-    value.serialize(&mut serializer)?;
-    Ok(serializer.into_inner())
+// Type tags written in front of every value in self-describing mode. `TRUE`, `FALSE`, `NONE` and
+// `SOME` double as the tags for booleans and options.
+const TAG_U8: u8 = 0x01;
+const TAG_U16: u8 = 0x02;
+const TAG_U32: u8 = 0x03;
+const TAG_U64: u8 = 0x04;
+const TAG_U128: u8 = 0x05;
+const TAG_I8: u8 = 0x11;
+const TAG_I16: u8 = 0x12;
+const TAG_I32: u8 = 0x13;
+const TAG_I64: u8 = 0x14;
+const TAG_I128: u8 = 0x15;
+const TAG_F32: u8 = 0x21;
+const TAG_F64: u8 = 0x22;
+const TAG_CHAR: u8 = 0x30;
+const TAG_STR: u8 = 0x31;
+const TAG_BYTES: u8 = 0x32;
+const TAG_SEQ: u8 = 0x40;
+const TAG_MAP: u8 = 0x41;
+/// A sequence whose elements run until `BREAK`.
+const TAG_SEQ_UNBOUNDED: u8 = 0x42;
+/// A map whose entries run until `BREAK`.
+const TAG_MAP_UNBOUNDED: u8 = 0x43;
+/// Variant index, variant name, then the payload.
+const TAG_VARIANT: u8 = 0x50;
+const TAG_UNIT: u8 = 0xf0;
+/// Precedes every element of a sequence or map of unknown length in compact mode.
+const MORE: u8 = 0xfe;
+/// Ends a sequence or map of unknown length.
+const BREAK: u8 = 0xff;
+
+/// How integers wider than a byte are written, including lengths and variant indices.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IntEncoding {
+    /// Always the full width of the type.
+    #[default]
+    Fixed,
+    /// LEB128, seven bits per byte with the high bit marking continuation. Signed integers are
+    /// zigzag mapped first so that small negative numbers stay short.
+    Varint,
 }
 
-pub struct Serializer {
-    buffer: Vec<u8>,
+/// Byte order of fixed-width integers and floats.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Endian {
+    #[default]
+    Big,
+    Little,
+    /// Whatever the target uses, matching `#[repr(C)]` readers on the same machine.
+    Native,
 }
 
-impl Serializer
+/// The one NaN and the one zero of canonical mode.
+fn canonical_f32(v: f32) -> f32 {
+    if v.is_nan() {
+        f32::from_bits(0x7fc0_0000)
+    } else if v == 0.0 {
+        0.0
+    } else {
+        v
+    }
+}
+
+fn canonical_f64(v: f64) -> f64 {
+    if v.is_nan() {
+        f64::from_bits(0x7ff8_0000_0000_0000)
+    } else if v == 0.0 {
+        0.0
+    } else {
+        v
+    }
+}
+
+/// Numbers that have a fixed-width encoding in every byte order.
+trait FixedWidth: Sized {
+    type Bytes: Default + AsRef<[u8]> + AsMut<[u8]>;
+
+    fn to_bytes(self, endian: Endian) -> Self::Bytes;
+
+    fn from_bytes(bytes: Self::Bytes, endian: Endian) -> Self;
+}
+
+macro_rules! fixed_width {
+    ($($ty:ty),*) => {
+        $(
+            impl FixedWidth for $ty {
+                type Bytes = [u8; core::mem::size_of::<$ty>()];
+
+                fn to_bytes(self, endian: Endian) -> Self::Bytes {
+                    match endian {
+                        Endian::Big => self.to_be_bytes(),
+                        Endian::Little => self.to_le_bytes(),
+                        Endian::Native => self.to_ne_bytes(),
+                    }
+                }
+
+                fn from_bytes(bytes: Self::Bytes, endian: Endian) -> Self {
+                    match endian {
+                        Endian::Big => Self::from_be_bytes(bytes),
+                        Endian::Little => Self::from_le_bytes(bytes),
+                        Endian::Native => Self::from_ne_bytes(bytes),
+                    }
+                }
+            }
+        )*
+    };
+}
+
+fixed_width!(u8, u16, u32, u64, u128, i8, i16, i32, i64, i128, f32, f64);
+
+/// Width of the length prefix in front of strings, byte arrays, sequences and maps.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LengthWidth {
+    U8,
+    U16,
+    #[default]
+    U32,
+    U64,
+}
+
+impl LengthWidth
+{
+    fn max(self) -> u64 {
+        match self {
+            LengthWidth::U8 => u8::MAX as u64,
+            LengthWidth::U16 => u16::MAX as u64,
+            LengthWidth::U32 => u32::MAX as u64,
+            LengthWidth::U64 => u64::MAX,
+        }
+    }
+}
+
+/// How structs are laid out when not in self-describing mode, which always writes them as maps
+/// keyed by field name.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum StructEncoding {
+    /// Just the field values in declaration order. Adding, removing or reordering fields breaks
+    /// existing data.
+    #[default]
+    Positional,
+    /// A field count, then every field as its name, the length of its value in bytes and the
+    /// value. Decoders skip fields they do not know and leave missing ones to `#[serde(default)]`.
+    Named,
+    /// A bitmap with one bit per field, then the values of the fields that are set. Fields left out
+    /// by `skip_serializing_if` and `None` options cost a bit each, and options that are `Some`
+    /// need no marker of their own. Both sides must agree on the list of fields.
+    Presence,
+}
+
+/// What decoding does with input left over after the top-level value.
+///
+/// Unless configured, input in memory rejects it and streams allow it, since checking a stream
+/// means waiting for it to end.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrailingBytes {
+    /// Ignore it.
+    Allow,
+    /// Fail with [`ErrorKind::TrailingData`]. For a stream this means reading until it ends.
+    Reject,
+}
+
+/// Format settings. A [`Deserializer`] must use the same settings as the [`Serializer`] that wrote
+/// its input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Config {
+    self_describing: bool,
+    int_encoding: IntEncoding,
+    endian: Endian,
+    length_width: LengthWidth,
+    struct_encoding: StructEncoding,
+    checksum: bool,
+    canonical: bool,
+    max_alloc: Option<usize>,
+    byte_limit: Option<u64>,
+    max_len: Option<usize>,
+    max_depth: usize,
+    /// `None` for the default of the input, see [`TrailingBytes`].
+    trailing_bytes: Option<TrailingBytes>,
+}
+
+impl Default for Config
+{
+    fn default() -> Self {
+        Self {
+            self_describing: false,
+            int_encoding: IntEncoding::default(),
+            endian: Endian::default(),
+            length_width: LengthWidth::default(),
+            struct_encoding: StructEncoding::default(),
+            checksum: false,
+            canonical: false,
+            max_alloc: None,
+            byte_limit: None,
+            max_len: None,
+            max_depth: 128,
+            trailing_bytes: None,
+        }
+    }
+}
+
+impl Config
 {
     pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Write a type tag in front of every value, and structs as maps keyed by field name.
+    ///
+    /// This makes the output decodable without knowing its type, which `deserialize_any`,
+    /// `#[serde(flatten)]`, untagged and internally tagged enums rely on. Off by default.
+    pub fn self_describing(mut self, enabled: bool) -> Self {
+        self.self_describing = enabled;
+        self
+    }
+
+    /// Fixed-width or variable-length integers, [`IntEncoding::Fixed`] by default.
+    pub fn int_encoding(mut self, encoding: IntEncoding) -> Self {
+        self.int_encoding = encoding;
+        self
+    }
+
+    /// Byte order of fixed-width numbers, [`Endian::Big`] by default. Varints are unaffected.
+    pub fn endian(mut self, endian: Endian) -> Self {
+        self.endian = endian;
+        self
+    }
+
+    /// Width of length prefixes, [`LengthWidth::U32`] by default. With varints it only bounds the
+    /// largest length that can be written.
+    ///
+    /// The largest value of the width marks sequences and maps whose length is not known up front.
+    pub fn length_width(mut self, width: LengthWidth) -> Self {
+        self.length_width = width;
+        self
+    }
+
+    /// Layout of structs, [`StructEncoding::Positional`] by default. Has no effect in
+    /// self-describing mode.
+    pub fn struct_encoding(mut self, encoding: StructEncoding) -> Self {
+        self.struct_encoding = encoding;
+        self
+    }
+
+    /// Follow every top-level value with the CRC32C of its bytes, big endian, which decoders check
+    /// before accepting the value. Off by default.
+    pub fn checksum(mut self, enabled: bool) -> Self {
+        self.checksum = enabled;
+        self
+    }
+
+    /// Give every value exactly one encoding, so that equal values encode to equal bytes that can
+    /// be hashed or signed. Off by default.
+    ///
+    /// Map entries are sorted by the bytes of their keys and duplicate keys are refused, which in
+    /// self-describing mode includes structs. Sequences and maps of unknown length are buffered to
+    /// write their count up front, NaNs are written as the one quiet NaN and `-0.0` as `0.0`.
+    /// Decoders reject input that is not in this form, including overlong varints. Without the
+    /// `alloc` feature maps and sequences of unknown length are refused.
+    pub fn canonical(mut self, enabled: bool) -> Self {
+        self.canonical = enabled;
+        self
+    }
+
+    /// Refuse to decode strings and byte arrays longer than `limit` bytes. Unlimited by default.
+    pub fn max_alloc(mut self, limit: usize) -> Self {
+        self.max_alloc = Some(limit);
+        self
+    }
+
+    /// Refuse to read more than `limit` bytes of input for one value. Unlimited by default.
+    pub fn byte_limit(mut self, limit: u64) -> Self {
+        self.byte_limit = Some(limit);
+        self
+    }
+
+    /// Refuse sequences and maps with more than `limit` elements. Unlimited by default.
+    pub fn max_len(mut self, limit: usize) -> Self {
+        self.max_len = Some(limit);
+        self
+    }
+
+    /// Refuse values nested deeper than `limit` sequences, maps, structs, enums and options, so
+    /// that hostile input cannot exhaust the stack. 128 by default.
+    pub fn max_depth(mut self, limit: usize) -> Self {
+        self.max_depth = limit;
+        self
+    }
+
+    /// Whether decoding accepts input past the value. By default `from_bytes` rejects it and
+    /// `from_reader` leaves the rest of the stream alone. Use [`Config::take_from_bytes`] to
+    /// decode several values out of one buffer.
+    pub fn trailing_bytes(mut self, policy: TrailingBytes) -> Self {
+        self.trailing_bytes = Some(policy);
+        self
+    }
+
+    #[cfg(feature = "alloc")]
+    pub fn to_bytes<T: Serialize>(self, value: &T) -> Result<Vec<u8>, Error> {
+        let mut serializer = Serializer::new(Vec::new()).with_config(self);
+        value.serialize(&mut serializer)?;
+        serializer.end()?;
+        Ok(serializer.into_inner())
+    }
+
+    pub fn to_writer<W: Write, T: Serialize>(self, writer: W, value: &T) -> Result<(), Error> {
+        let mut serializer = Serializer::new(writer).with_config(self);
+        value.serialize(&mut serializer)?;
+        serializer.end()
+    }
+
+    /// Encode `value` into `buf` and return the part of it that was written. Fails with
+    /// [`ErrorKind::BufferFull`] rather than allocate, except for the buffering that structs in
+    /// [`StructEncoding::Named`] and [`StructEncoding::Presence`] need.
+    pub fn to_slice<'b, T: Serialize>(self, value: &T, buf: &'b mut [u8]) -> Result<&'b mut [u8], Error> {
+        let mut serializer = Serializer::new(SliceWriter { buf, len: 0 }).with_config(self);
+        value.serialize(&mut serializer)?;
+        serializer.end()?;
+        let writer = serializer.into_inner();
+        Ok(&mut writer.buf[..writer.len])
+    }
+
+    /// Append the encoding of `value` to `vec`, reusing its allocation. On failure `vec` is left as
+    /// it was.
+    #[cfg(feature = "alloc")]
+    pub fn to_vec_into<T: Serialize>(self, value: &T, vec: &mut Vec<u8>) -> Result<(), Error> {
+        let start = vec.len();
+        let result = self.to_writer(&mut *vec, value);
+        if result.is_err() {
+            vec.truncate(start);
+        }
+        result
+    }
+
+    /// Number of bytes [`Config::to_bytes`] would produce for `value`, found by running the same
+    /// serializer into a writer that only counts. Structs in [`StructEncoding::Named`] and
+    /// [`StructEncoding::Presence`] are still buffered on the way.
+    pub fn serialized_size<T: Serialize>(self, value: &T) -> Result<u64, Error> {
+        let mut serializer = Serializer::new(SizeCounter { len: 0 }).with_config(self);
+        value.serialize(&mut serializer)?;
+        serializer.end()?;
+        Ok(serializer.into_inner().len)
+    }
+
+    /// Decode a `T` from `bytes` under this configuration; [`from_bytes`] with the default one.
+    /// Methods taking `self` cannot be called `from_*` without reading as constructors of
+    /// `Config`, hence the longer name.
+    pub fn deserialize_from_bytes<'de, T>(self, bytes: &'de [u8]) -> Result<T, Error>
+    where
+        T: serde::Deserialize<'de>,
+    {
+        let mut deserializer = Deserializer::from_slice(bytes).with_config(self);
+        deserializer.verify_checksum_upfront()?;
+        deserializer.deserialize_value()
+    }
+
+    /// Decode the value at the start of `bytes` and return it with the rest of the input, whatever
+    /// the trailing bytes policy.
+    pub fn take_from_bytes<'de, T>(self, bytes: &'de [u8]) -> Result<(T, &'de [u8]), Error>
+    where
+        T: serde::Deserialize<'de>,
+    {
+        let mut deserializer = Deserializer::from_slice(bytes).with_config(self);
+        let value = T::deserialize(&mut deserializer).map_err(|err| deserializer.fix_position(err))?;
+        deserializer.verify_checksum()?;
+        Ok((value, deserializer.remaining()))
+    }
+
+    /// Decode a `T` from `reader` under this configuration; [`from_reader`] with the default
+    /// one, named like [`Config::deserialize_from_bytes`] for the same reason.
+    #[cfg(feature = "std")]
+    pub fn deserialize_from_reader<T, R>(self, reader: R) -> Result<T, Error>
+    where
+        T: serde::de::DeserializeOwned,
+        R: std::io::Read,
+    {
+        let mut deserializer = Deserializer::new(reader).with_config(self);
+        deserializer.deserialize_value()
+    }
+}
+
+#[cfg(feature = "alloc")]
+pub fn to_bytes<T: Serialize>(value: &T) -> Result<Vec<u8>, Error> {
+    Config::default().to_bytes(value)
+}
+
+pub fn to_writer<W: Write, T: Serialize>(writer: W, value: &T) -> Result<(), Error> {
+    Config::default().to_writer(writer, value)
+}
+
+pub fn to_slice<'b, T: Serialize>(value: &T, buf: &'b mut [u8]) -> Result<&'b mut [u8], Error> {
+    Config::default().to_slice(value, buf)
+}
+
+#[cfg(feature = "alloc")]
+pub fn to_vec_into<T: Serialize>(value: &T, vec: &mut Vec<u8>) -> Result<(), Error> {
+    Config::default().to_vec_into(value, vec)
+}
+
+pub fn serialized_size<T: Serialize>(value: &T) -> Result<u64, Error> {
+    Config::default().serialized_size(value)
+}
+
+/// Where the [`Serializer`] puts its bytes.
+///
+/// Implemented for every [`std::io::Write`] with the `std` feature, and for `Vec<u8>` with only
+/// `alloc`.
+pub trait Write
+{
+    /// Take all of `bytes` or fail.
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), Error>;
+}
+
+#[cfg(feature = "std")]
+impl<W: std::io::Write + ?Sized> Write for W
+{
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        std::io::Write::write_all(self, bytes).map_err(Error::io)
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl<W: Write + ?Sized> Write for &mut W
+{
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        (**self).write_bytes(bytes)
+    }
+}
+
+#[cfg(all(feature = "alloc", not(feature = "std")))]
+impl Write for Vec<u8>
+{
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        self.extend_from_slice(bytes);
+        Ok(())
+    }
+}
+
+/// A writer into a fixed buffer that refuses anything past its end.
+struct SliceWriter<'b> {
+    buf: &'b mut [u8],
+    len: usize,
+}
+
+impl<'b> Write for SliceWriter<'b>
+{
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        let end = self.len + bytes.len();
+        if end > self.buf.len() {
+            return Err(Error::new(ErrorKind::BufferFull, format_args!("value does not fit into a buffer of {} bytes", self.buf.len())));
+        }
+        self.buf[self.len..end].copy_from_slice(bytes);
+        self.len = end;
+        Ok(())
+    }
+}
+
+/// Passes formatted text to the serializer, or only counts it without one.
+#[cfg(not(feature = "alloc"))]
+struct StrWriter<'a, W: Write> {
+    ser: Option<&'a mut Serializer<W>>,
+    len: usize,
+    error: Option<Error>,
+}
+
+#[cfg(not(feature = "alloc"))]
+impl<'a, W: Write> core::fmt::Write for StrWriter<'a, W>
+{
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        self.len += s.len();
+        if let Some(ser) = &mut self.ser {
+            ser.write(s.as_bytes()).map_err(|err| {
+                self.error = Some(err);
+                core::fmt::Error
+            })?;
+        }
+        Ok(())
+    }
+}
+
+/// A writer that drops what it is given and keeps count.
+struct SizeCounter {
+    len: u64,
+}
+
+impl Write for SizeCounter
+{
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        self.len += bytes.len() as u64;
+        Ok(())
+    }
+}
+
+pub struct Serializer<W: Write> {
+    writer: W,
+    config: Config,
+    /// CRC32C of the top-level value written so far, when checksums are on.
+    crc: u32,
+}
+
+impl<W: Write> Serializer<W>
+{
+    pub fn new(writer: W) -> Self {
         Self {
-            buffer: Vec::new(),
+            writer,
+            config: Config::default(),
+            crc: 0,
+        }
+    }
+
+    pub fn with_config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    /// Finish a top-level value, which writes the checksum trailer if [`Config::checksum`] is on.
+    pub fn end(&mut self) -> Result<(), Error> {
+        if self.config.checksum {
+            let crc = core::mem::take(&mut self.crc);
+            self.writer.write_bytes(&crc.to_be_bytes())?;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        if self.config.checksum {
+            self.crc = checksum::crc32c_append(self.crc, bytes);
+        }
+        self.writer.write_bytes(bytes)
+    }
+
+    fn write_tag(&mut self, tag: u8) -> Result<(), Error> {
+        if self.config.self_describing {
+            self.write(&[tag])
+        } else {
+            Ok(())
+        }
+    }
+
+    fn write_len(&mut self, len: usize, what: &str) -> Result<(), Error> {
+        // check if the length fits into the configured width
+        if len as u64 > self.config.length_width.max() {
+            return Err(Error::new(ErrorKind::LengthOverflow, format_args!("{} length is too large", what)));
+        }
+        self.write_len_unchecked(len as u64)
+    }
+
+    fn write_len_unchecked(&mut self, len: u64) -> Result<(), Error> {
+        match self.config.length_width {
+            LengthWidth::U8 => self.write_unsigned(len as u8),
+            LengthWidth::U16 => self.write_unsigned(len as u16),
+            LengthWidth::U32 => self.write_unsigned(len as u32),
+            LengthWidth::U64 => self.write_unsigned(len),
+        }
+    }
+
+    /// Write the element count of a sequence or map. The largest value of the length width is
+    /// reserved to mark a count that is not known up front, elements then carry `MORE` in front
+    /// and `BREAK` after the last one.
+    fn write_count(&mut self, len: Option<usize>, what: &str) -> Result<(), Error> {
+        let unbounded = self.config.length_width.max();
+        match len {
+            Some(len) if len as u64 >= unbounded => {
+                Err(Error::new(ErrorKind::LengthOverflow, format_args!("{} length is too large", what)))
+            }
+            Some(len) => self.write_len_unchecked(len as u64),
+            None => self.write_len_unchecked(unbounded),
+        }
+    }
+
+    fn write_fixed<T: FixedWidth>(&mut self, v: T) -> Result<(), Error> {
+        self.write(v.to_bytes(self.config.endian).as_ref())
+    }
+
+    fn write_unsigned<T: FixedWidth + Into<u128>>(&mut self, v: T) -> Result<(), Error> {
+        match self.config.int_encoding {
+            IntEncoding::Fixed => self.write_fixed(v),
+            IntEncoding::Varint => self.write_varint(v.into()),
+        }
+    }
+
+    fn write_signed<T: FixedWidth + Into<i128>>(&mut self, v: T) -> Result<(), Error> {
+        match self.config.int_encoding {
+            IntEncoding::Fixed => self.write_fixed(v),
+            IntEncoding::Varint => {
+                let v = v.into();
+                self.write_varint(((v << 1) ^ (v >> 127)) as u128)
+            }
+        }
+    }
+
+    fn write_varint(&mut self, mut v: u128) -> Result<(), Error> {
+        let mut buf = [0; 19];
+        let mut len = 0;
+        loop {
+            let byte = (v & 0x7f) as u8;
+            v >>= 7;
+            if v == 0 {
+                buf[len] = byte;
+                len += 1;
+                break;
+            }
+            buf[len] = byte | 0x80;
+            len += 1;
+        }
+        self.write(&buf[..len])
+    }
+
+    /// Write `value` behind its length in bytes, so that readers can skip it without knowing its type.
+    #[cfg(feature = "alloc")]
+    fn write_delimited<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+        let mut inner = Serializer::new(Vec::new()).with_config(self.config);
+        value.serialize(&mut inner)?;
+        let bytes = inner.into_inner();
+        self.write_len(bytes.len(), "field")?;
+        self.write(&bytes)
+    }
+
+    /// Without a buffer to hold `value` it is serialized twice, once to measure it.
+    #[cfg(not(feature = "alloc"))]
+    fn write_delimited<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+        let mut counter = Serializer::new(SizeCounter { len: 0 }).with_config(self.config);
+        value.serialize(&mut counter)?;
+        let len = usize::try_from(counter.into_inner().len).map_err(|_| Error::new(ErrorKind::LengthOverflow, "field length is too large"))?;
+        self.write_len(len, "field")?;
+        value.serialize(self)
+    }
+
+    fn write_variant(&mut self, variant_index: u32, variant: &'static str) -> Result<(), Error> {
+        self.write_tag(TAG_VARIANT)?;
+        self.write_unsigned(variant_index)?;
+        if self.config.self_describing {
+            self.write_len(variant.len(), "variant name")?;
+            self.write(variant.as_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+/// State for sequences, maps and structs, which may have to be closed with `BREAK` or have their
+/// fields held back until the presence bitmap is known or the entries are sorted.
+pub struct Compound<'a, W: Write> {
+    ser: &'a mut Serializer<W>,
+    unbounded: bool,
+    presence: Option<Presence>,
+    buffered: Option<Buffered>,
+}
+
+/// The elements or entries of a sequence or map in canonical mode, held back until their count is
+/// known and, for maps, until they are sorted.
+#[cfg(feature = "alloc")]
+struct Buffered {
+    sorted: bool,
+    /// Key and value of every entry, or an empty key and the element.
+    entries: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+#[cfg(not(feature = "alloc"))]
+enum Buffered {}
+
+#[cfg(feature = "alloc")]
+impl Buffered
+{
+    fn new(sorted: bool) -> Result<Self, Error> {
+        Ok(Self { sorted, entries: Vec::new() })
+    }
+
+    fn encode<T: ?Sized + Serialize>(config: Config, value: &T) -> Result<Vec<u8>, Error> {
+        let mut ser = Serializer::new(Vec::new()).with_config(config);
+        value.serialize(&mut ser)?;
+        Ok(ser.into_inner())
+    }
+
+    fn element<T: ?Sized + Serialize>(&mut self, config: Config, value: &T) -> Result<(), Error> {
+        self.entries.push((Vec::new(), Self::encode(config, value)?));
+        Ok(())
+    }
+
+    fn key<T: ?Sized + Serialize>(&mut self, config: Config, key: &T) -> Result<(), Error> {
+        self.entries.push((Self::encode(config, key)?, Vec::new()));
+        Ok(())
+    }
+
+    fn value<T: ?Sized + Serialize>(&mut self, config: Config, value: &T) -> Result<(), Error> {
+        let bytes = Self::encode(config, value)?;
+        match self.entries.last_mut() {
+            Some((_, entry)) => *entry = bytes,
+            None => return Err(Error::new(ErrorKind::Custom, "map value without a key")),
         }
+        Ok(())
+    }
+
+    fn finish<W: Write>(mut self, ser: &mut Serializer<W>) -> Result<(), Error> {
+        if self.sorted {
+            self.entries.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+            if self.entries.windows(2).any(|pair| pair[0].0 == pair[1].0) {
+                return Err(Error::new(ErrorKind::NonCanonical, "map has duplicate keys"));
+            }
+        }
+        ser.write_count(Some(self.entries.len()), if self.sorted { "map" } else { "sequence" })?;
+        for (key, value) in &self.entries {
+            ser.write(key)?;
+            ser.write(value)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(feature = "alloc"))]
+impl Buffered
+{
+    fn new(_sorted: bool) -> Result<Self, Error> {
+        Err(Error::new(ErrorKind::Unsupported, "canonical maps and sequences of unknown length need the alloc feature"))
+    }
+
+    fn element<T: ?Sized + Serialize>(&mut self, _config: Config, _value: &T) -> Result<(), Error> {
+        match *self {}
+    }
+
+    fn key<T: ?Sized + Serialize>(&mut self, _config: Config, _key: &T) -> Result<(), Error> {
+        match *self {}
+    }
+
+    fn value<T: ?Sized + Serialize>(&mut self, _config: Config, _value: &T) -> Result<(), Error> {
+        match *self {}
+    }
+
+    fn finish<W: Write>(self, _ser: &mut Serializer<W>) -> Result<(), Error> {
+        match self {}
+    }
+}
+
+/// The fields of a struct in [`StructEncoding::Presence`] written so far.
+#[cfg(feature = "alloc")]
+#[derive(Default)]
+struct Presence {
+    bitmap: Vec<u8>,
+    len: usize,
+    values: Vec<u8>,
+}
+
+/// Without `alloc` there is nowhere to hold the fields back until the bitmap is complete.
+#[cfg(not(feature = "alloc"))]
+enum Presence {}
+
+#[cfg(feature = "alloc")]
+impl Presence
+{
+    fn new() -> Result<Self, Error> {
+        Ok(Self::default())
+    }
+
+    fn push(&mut self, present: bool) {
+        if self.len.is_multiple_of(8) {
+            self.bitmap.push(0);
+        }
+        if present {
+            self.bitmap[self.len / 8] |= 1 << (self.len % 8);
+        }
+        self.len += 1;
+    }
+
+    fn field<T: ?Sized + Serialize>(&mut self, config: Config, value: &T) -> Result<(), Error> {
+        let mut ser = Serializer::new(core::mem::take(&mut self.values)).with_config(config);
+        let mut present = true;
+        let result = value.serialize(FieldSerializer { ser: &mut ser, present: &mut present });
+        self.values = ser.into_inner();
+        result?;
+        self.push(present);
+        Ok(())
+    }
+
+    fn finish<W: Write>(self, ser: &mut Serializer<W>) -> Result<(), Error> {
+        ser.write(&self.bitmap)?;
+        ser.write(&self.values)
+    }
+}
+
+#[cfg(not(feature = "alloc"))]
+impl Presence
+{
+    fn new() -> Result<Self, Error> {
+        Err(Error::new(ErrorKind::Unsupported, "presence structs need the alloc feature"))
+    }
+
+    fn push(&mut self, _present: bool) {
+        match *self {}
+    }
+
+    fn field<T: ?Sized + Serialize>(&mut self, _config: Config, _value: &T) -> Result<(), Error> {
+        match *self {}
+    }
+
+    fn finish<W: Write>(self, _ser: &mut Serializer<W>) -> Result<(), Error> {
+        match self {}
+    }
+}
+
+impl<'a, W: Write> Compound<'a, W>
+{
+    /// Mark that another element follows, self-describing output relies on its tags instead.
+    fn more(&mut self) -> Result<(), Error> {
+        if self.unbounded && !self.ser.config.self_describing {
+            self.ser.write(&[MORE])?;
+        }
+        Ok(())
     }
 
-    pub fn into_inner(self) -> Vec<u8> {
-        self.buffer
+    fn end(self) -> Result<(), Error> {
+        if self.unbounded {
+            self.ser.write(&[BREAK])?;
+        }
+        if let Some(presence) = self.presence {
+            presence.finish(self.ser)?;
+        }
+        if let Some(buffered) = self.buffered {
+            buffered.finish(self.ser)?;
+        }
+        Ok(())
     }
 }
 
-impl<'a> serde::Serializer for &'a mut Serializer
+impl<'a, W: Write> serde::Serializer for &'a mut Serializer<W>
 {
     type Ok = ();
     type Error = Error;
 
-    type SerializeSeq = Self;
+    type SerializeSeq = Compound<'a, W>;
     type SerializeTuple = Self;
     type SerializeTupleStruct = Self;
     type SerializeTupleVariant = Self;
-    type SerializeMap = Self;
-    type SerializeStruct = Self;
-    type SerializeStructVariant = Self;
+    type SerializeMap = Compound<'a, W>;
+    type SerializeStruct = Compound<'a, W>;
+    type SerializeStructVariant = Compound<'a, W>;
 
     fn serialize_bool(self, v: bool) -> Result<Self::Ok, Self::Error> {
         if v {
-            TRUE.serialize(&mut *self)
+            self.write(&[TRUE])
         } else {
-            FALSE.serialize(&mut *self)
+            self.write(&[FALSE])
         }
     }
 
     fn serialize_i8(self, v: i8) -> Result<Self::Ok, Self::Error> {
-        Ok(self.buffer.extend(v.to_be_bytes().to_vec()))
+        self.write_tag(TAG_I8)?;
+        self.write_fixed(v)
     }
 
     fn serialize_i16(self, v: i16) -> Result<Self::Ok, Self::Error> {
-        Ok(self.buffer.extend(v.to_be_bytes().to_vec()))
+        self.write_tag(TAG_I16)?;
+        self.write_signed(v)
     }
 
     fn serialize_i32(self, v: i32) -> Result<Self::Ok, Self::Error> {
-        Ok(self.buffer.extend(v.to_be_bytes().to_vec()))
+        self.write_tag(TAG_I32)?;
+        self.write_signed(v)
     }
 
     fn serialize_i64(self, v: i64) -> Result<Self::Ok, Self::Error> {
-        Ok(self.buffer.extend(v.to_be_bytes().to_vec()))
+        self.write_tag(TAG_I64)?;
+        self.write_signed(v)
+    }
+
+    fn serialize_i128(self, v: i128) -> Result<Self::Ok, Self::Error> {
+        self.write_tag(TAG_I128)?;
+        self.write_signed(v)
     }
 
     fn serialize_u8(self, v: u8) -> Result<Self::Ok, Self::Error> {
-        Ok(self.buffer.extend(v.to_be_bytes().to_vec()))
+        self.write_tag(TAG_U8)?;
+        self.write_fixed(v)
     }
 
     fn serialize_u16(self, v: u16) -> Result<Self::Ok, Self::Error> {
-        Ok(self.buffer.extend(v.to_be_bytes().to_vec()))
+        self.write_tag(TAG_U16)?;
+        self.write_unsigned(v)
     }
 
     fn serialize_u32(self, v: u32) -> Result<Self::Ok, Self::Error> {
-        Ok(self.buffer.extend(v.to_be_bytes().to_vec()))
+        self.write_tag(TAG_U32)?;
+        self.write_unsigned(v)
     }
 
     fn serialize_u64(self, v: u64) -> Result<Self::Ok, Self::Error> {
-        Ok(self.buffer.extend(v.to_be_bytes().to_vec()))
+        self.write_tag(TAG_U64)?;
+        self.write_unsigned(v)
+    }
+
+    fn serialize_u128(self, v: u128) -> Result<Self::Ok, Self::Error> {
+        self.write_tag(TAG_U128)?;
+        self.write_unsigned(v)
     }
 
     fn serialize_f32(self, v: f32) -> Result<Self::Ok, Self::Error> {
-        Ok(self.buffer.extend(v.to_be_bytes().to_vec()))
+        self.write_tag(TAG_F32)?;
+        self.write_fixed(if self.config.canonical { canonical_f32(v) } else { v })
     }
 
     fn serialize_f64(self, v: f64) -> Result<Self::Ok, Self::Error> {
-        Ok(self.buffer.extend(v.to_be_bytes().to_vec()))
+        self.write_tag(TAG_F64)?;
+        self.write_fixed(if self.config.canonical { canonical_f64(v) } else { v })
     }
 
     fn serialize_char(self, v: char) -> Result<Self::Ok, Self::Error> {
-        let bytes = v.to_string().into_bytes();
-        self.serialize_u8(bytes.len() as u8)?;
-        Ok(self.buffer.extend(bytes))
+        let mut buf = [0; 4];
+        let bytes = v.encode_utf8(&mut buf).as_bytes();
+        self.write_tag(TAG_CHAR)?;
+        self.write(&[bytes.len() as u8])?;
+        self.write(bytes)
     }
 
     fn serialize_str(self, v: &str) -> Result<Self::Ok, Self::Error> {
-        let bytes = v.to_string().into_bytes();
-        // assert that the string length fits into a u32
-        if bytes.len() > 0xffffffff {
-            return Err(Error::custom("string length is too large"));
+        self.write_tag(TAG_STR)?;
+        self.write_len(v.len(), "string")?;
+        self.write(v.as_bytes())
+    }
+
+    /// Without `alloc` there is no string to collect into, so `value` is formatted twice, once to
+    /// measure it.
+    #[cfg(not(feature = "alloc"))]
+    fn collect_str<T: ?Sized + core::fmt::Display>(self, value: &T) -> Result<Self::Ok, Self::Error> {
+        use core::fmt::Write as _;
+        let mut counter = StrWriter::<W> { ser: None, len: 0, error: None };
+        let _ = write!(counter, "{}", value);
+        let len = counter.len;
+        self.write_tag(TAG_STR)?;
+        self.write_len(len, "string")?;
+        let mut writer = StrWriter { ser: Some(self), len: 0, error: None };
+        if write!(writer, "{}", value).is_err() {
+            return Err(writer.error.unwrap_or_else(|| Error::new(ErrorKind::Custom, "formatting failed")));
         }
-        self.serialize_u32(bytes.len() as u32)?;
-        Ok(self.buffer.extend(bytes))
+        if writer.len != len {
+            return Err(Error::new(ErrorKind::Custom, "value formatted differently the second time"));
+        }
+        Ok(())
     }
 
     fn serialize_bytes(self, v: &[u8]) -> Result<Self::Ok, Self::Error> {
-        // assert that the byte length fits into a u32
-        if v.len() > 0xffffffff {
-            return Err(Error::custom("byte length is too large"));
-        }
-        self.serialize_u32(v.len() as u32)?;
-        Ok(self.buffer.extend(v))
+        self.write_tag(TAG_BYTES)?;
+        self.write_len(v.len(), "byte")?;
+        self.write(v)
     }
 
     fn serialize_none(self) -> Result<Self::Ok, Self::Error> {
-        NONE.serialize(&mut *self)
+        self.write(&[NONE])
     }
 
-    fn serialize_some<T: ?Sized>(self, value: &T) -> Result<Self::Ok, Self::Error>
-    where
-        T: serde::Serialize,
-    {
-        SOME.serialize(&mut *self)?;
+    fn serialize_some<T: ?Sized + serde::Serialize>(self, value: &T) -> Result<Self::Ok, Self::Error> {
+        self.write(&[SOME])?;
         value.serialize(&mut *self)?;
         Ok(())
     }
 
     fn serialize_unit(self) -> Result<Self::Ok, Self::Error> {
-        Ok(())
+        self.write_tag(TAG_UNIT)
     }
 
     fn serialize_unit_struct(self, _name: &'static str) -> Result<Self::Ok, Self::Error> {
-        Ok(())
+        self.write_tag(TAG_UNIT)
     }
 
-    fn serialize_unit_variant(self, _name: &'static str, _variant_index: u32, variant: &'static str) -> Result<Self::Ok, Self::Error> {
-        self.serialize_u32(_variant_index)
+    fn serialize_unit_variant(self, _name: &'static str, variant_index: u32, variant: &'static str) -> Result<Self::Ok, Self::Error> {
+        self.write_variant(variant_index, variant)?;
+        self.write_tag(TAG_UNIT)
     }
-    
-    fn serialize_newtype_struct<T: ?Sized>(self, _name: &'static str, value: &T) -> Result<Self::Ok, Self::Error>
-    where
-        T: serde::Serialize,
-    {
+
+    fn serialize_newtype_struct<T: ?Sized + serde::Serialize>(self, _name: &'static str, value: &T) -> Result<Self::Ok, Self::Error> {
         value.serialize(self)
     }
 
-    fn serialize_newtype_variant<T: ?Sized>(self, _name: &'static str, _variant_index: u32, variant: &'static str, value: &T) -> Result<Self::Ok, Self::Error>
-    where
-        T: serde::Serialize,
-    {
-        _variant_index.serialize(&mut *self)?;
+    fn serialize_newtype_variant<T: ?Sized + serde::Serialize>(self, _name: &'static str, variant_index: u32, variant: &'static str, value: &T) -> Result<Self::Ok, Self::Error> {
+        self.write_variant(variant_index, variant)?;
         value.serialize(&mut *self)?;
         Ok(())
     }
 
-    fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq, Self::Error> {
-        // error if len is None
-        let len = _len.ok_or(Error::custom("sequence length is unknown"))?;
-        // check if the length fits into a u32
-        if len > 0xffffffff {
-            return Err(Error::custom("sequence length is too large"));
+    fn serialize_seq(self, len: Option<usize>) -> Result<Self::SerializeSeq, Self::Error> {
+        if self.config.canonical && len.is_none() {
+            self.write_tag(TAG_SEQ)?;
+            return Ok(Compound { ser: self, unbounded: false, presence: None, buffered: Some(Buffered::new(false)?) });
         }
-        (len as u32).serialize(&mut *self)?;
-        Ok(self)
+        if self.config.self_describing && len.is_none() {
+            self.write(&[TAG_SEQ_UNBOUNDED])?;
+        } else {
+            self.write_tag(TAG_SEQ)?;
+            self.write_count(len, "sequence")?;
+        }
+        Ok(Compound { ser: self, unbounded: len.is_none(), presence: None, buffered: None })
     }
 
     fn serialize_tuple(self, len: usize) -> Result<Self::SerializeTuple, Self::Error> {
+        if self.config.self_describing {
+            self.write(&[TAG_SEQ])?;
+            self.write_len(len, "tuple")?;
+        }
         Ok(self)
     }
 
     fn serialize_tuple_struct(self, _name: &'static str, len: usize) -> Result<Self::SerializeTupleStruct, Self::Error> {
-        Ok(self)
+        self.serialize_tuple(len)
     }
 
-    fn serialize_tuple_variant(self, _name: &'static str, _variant_index: u32, variant: &'static str, len: usize) -> Result<Self::SerializeTupleVariant, Self::Error> {
-        _variant_index.serialize(&mut *self)?;
-        Ok(self)
+    fn serialize_tuple_variant(self, _name: &'static str, variant_index: u32, variant: &'static str, len: usize) -> Result<Self::SerializeTupleVariant, Self::Error> {
+        self.write_variant(variant_index, variant)?;
+        self.serialize_tuple(len)
     }
 
-    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap, Self::Error> {
-        // error if len is None
-        let len = _len.ok_or(Error::custom("map length is unknown"))?;
-        // check if the length fits into a u32
-        if len > 0xffffffff {
-            return Err(Error::custom("map length is too large"));
+    fn serialize_map(self, len: Option<usize>) -> Result<Self::SerializeMap, Self::Error> {
+        if self.config.canonical {
+            self.write_tag(TAG_MAP)?;
+            return Ok(Compound { ser: self, unbounded: false, presence: None, buffered: Some(Buffered::new(true)?) });
         }
-        (len as u32).serialize(&mut *self)?;
-        Ok(self)
+        if self.config.self_describing && len.is_none() {
+            self.write(&[TAG_MAP_UNBOUNDED])?;
+        } else {
+            self.write_tag(TAG_MAP)?;
+            self.write_count(len, "map")?;
+        }
+        Ok(Compound { ser: self, unbounded: len.is_none(), presence: None, buffered: None })
     }
 
     fn serialize_struct(self, _name: &'static str, len: usize) -> Result<Self::SerializeStruct, Self::Error> {
-        Ok(self)
+        if self.config.self_describing {
+            self.write(&[TAG_MAP])?;
+            if self.config.canonical {
+                return Ok(Compound { ser: self, unbounded: false, presence: None, buffered: Some(Buffered::new(true)?) });
+            }
+            self.write_len(len, "struct")?;
+        } else if self.config.struct_encoding == StructEncoding::Named {
+            self.write_len(len, "struct")?;
+        }
+        let presence = match self.config.struct_encoding {
+            StructEncoding::Presence if !self.config.self_describing => Some(Presence::new()?),
+            _ => None,
+        };
+        Ok(Compound { ser: self, unbounded: false, presence, buffered: None })
     }
 
-    fn serialize_struct_variant(self, _name: &'static str, _variant_index: u32, variant: &'static str, len: usize) -> Result<Self::SerializeStructVariant, Self::Error> {
-        _variant_index.serialize(&mut *self)?;
-        Ok(self)
+    fn serialize_struct_variant(self, _name: &'static str, variant_index: u32, variant: &'static str, len: usize) -> Result<Self::SerializeStructVariant, Self::Error> {
+        self.write_variant(variant_index, variant)?;
+        self.serialize_struct(variant, len)
     }
 }
 
-impl<'a> serde::ser::SerializeSeq for &'a mut Serializer
+impl<'a, W: Write> serde::ser::SerializeSeq for Compound<'a, W>
 {
     type Ok = ();
     type Error = Error;
 
-    fn serialize_element<T: ?Sized>(&mut self, value: &T) -> Result<(), Self::Error>
-    where
-        T: serde::Serialize,
-    {
+    fn serialize_element<T: ?Sized + serde::Serialize>(&mut self, value: &T) -> Result<(), Self::Error> {
+        if let Some(buffered) = &mut self.buffered {
+            return buffered.element(self.ser.config, value);
+        }
+        self.more()?;
+        value.serialize(&mut *self.ser)
+    }
+
+    fn end(self) -> Result<Self::Ok, Self::Error> {
+        Compound::end(self)
+    }
+}
+
+impl<W: Write> serde::ser::SerializeTuple for &mut Serializer<W>
+{
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: ?Sized + serde::Serialize>(&mut self, value: &T) -> Result<(), Self::Error> {
         value.serialize(&mut **self)
     }
 
@@ -256,15 +1290,12 @@ impl<'a> serde::ser::SerializeSeq for &'a mut Serializer
     }
 }
 
-impl<'a> serde::ser::SerializeTuple for &'a mut Serializer
+impl<W: Write> serde::ser::SerializeTupleStruct for &mut Serializer<W>
 {
     type Ok = ();
     type Error = Error;
 
-    fn serialize_element<T: ?Sized>(&mut self, value: &T) -> Result<(), Self::Error>
-    where
-        T: serde::Serialize,
-    {
+    fn serialize_field<T: ?Sized + serde::Serialize>(&mut self, value: &T) -> Result<(), Self::Error> {
         value.serialize(&mut **self)
     }
 
@@ -273,15 +1304,12 @@ impl<'a> serde::ser::SerializeTuple for &'a mut Serializer
     }
 }
 
-impl<'a> serde::ser::SerializeTupleStruct for &'a mut Serializer
+impl<W: Write> serde::ser::SerializeTupleVariant for &mut Serializer<W>
 {
     type Ok = ();
     type Error = Error;
 
-    fn serialize_field<T: ?Sized>(&mut self, value: &T) -> Result<(), Self::Error>
-    where
-        T: serde::Serialize,
-    {
+    fn serialize_field<T: ?Sized + serde::Serialize>(&mut self, value: &T) -> Result<(), Self::Error> {
         value.serialize(&mut **self)
     }
 
@@ -290,214 +1318,931 @@ impl<'a> serde::ser::SerializeTupleStruct for &'a mut Serializer
     }
 }
 
-impl<'a> serde::ser::SerializeTupleVariant for &'a mut Serializer
+impl<'a, W: Write> serde::ser::SerializeMap for Compound<'a, W>
 {
     type Ok = ();
     type Error = Error;
 
-    fn serialize_field<T: ?Sized>(&mut self, value: &T) -> Result<(), Self::Error>
-    where
-        T: serde::Serialize,
-    {
-        value.serialize(&mut **self)
+    fn serialize_key<T: ?Sized + serde::Serialize>(&mut self, key: &T) -> Result<(), Self::Error> {
+        if let Some(buffered) = &mut self.buffered {
+            return buffered.key(self.ser.config, key);
+        }
+        self.more()?;
+        key.serialize(&mut *self.ser)
+    }
+
+    fn serialize_value<T: ?Sized + serde::Serialize>(&mut self, value: &T) -> Result<(), Self::Error> {
+        if let Some(buffered) = &mut self.buffered {
+            return buffered.value(self.ser.config, value);
+        }
+        value.serialize(&mut *self.ser)
+    }
+
+    fn end(self) -> Result<Self::Ok, Self::Error> {
+        Compound::end(self)
+    }
+}
+
+impl<'a, W: Write> serde::ser::SerializeStruct for Compound<'a, W>
+{
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: ?Sized + serde::Serialize>(&mut self, key: &'static str, value: &T) -> Result<(), Self::Error> {
+        if let Some(presence) = &mut self.presence {
+            return presence.field(self.ser.config, value);
+        }
+        if let Some(buffered) = &mut self.buffered {
+            buffered.key(self.ser.config, key)?;
+            return buffered.value(self.ser.config, value);
+        }
+        if self.ser.config.self_describing {
+            serde::Serializer::serialize_str(&mut *self.ser, key)?;
+        } else if self.ser.config.struct_encoding == StructEncoding::Named {
+            serde::Serializer::serialize_str(&mut *self.ser, key)?;
+            return self.ser.write_delimited(value);
+        }
+        value.serialize(&mut *self.ser)
+    }
+
+    fn skip_field(&mut self, key: &'static str) -> Result<(), Self::Error> {
+        if let Some(presence) = &mut self.presence {
+            presence.push(false);
+            return Ok(());
+        }
+        // Maps and named structs only count the fields that are there.
+        if self.ser.config.self_describing || self.ser.config.struct_encoding == StructEncoding::Named {
+            return Ok(());
+        }
+        Err(Error::new(ErrorKind::Unsupported, format_args!("field {} is skipped, which positional structs cannot represent", key)))
+    }
+
+    fn end(self) -> Result<Self::Ok, Self::Error> {
+        Compound::end(self)
+    }
+}
+
+impl<'a, W: Write> serde::ser::SerializeStructVariant for Compound<'a, W>
+{
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: ?Sized + serde::Serialize>(&mut self, key: &'static str, value: &T) -> Result<(), Self::Error> {
+        serde::ser::SerializeStruct::serialize_field(self, key, value)
+    }
+
+    fn skip_field(&mut self, key: &'static str) -> Result<(), Self::Error> {
+        serde::ser::SerializeStruct::skip_field(self, key)
     }
 
     fn end(self) -> Result<Self::Ok, Self::Error> {
+        Compound::end(self)
+    }
+}
+
+/// Forward serializer methods to the wrapped [`Serializer`].
+#[cfg(feature = "alloc")]
+macro_rules! forward_to_ser {
+    ($($method:ident($($arg:ident: $ty:ty),*) -> $ok:ty;)*) => {
+        $(
+            fn $method(self, $($arg: $ty),*) -> Result<$ok, Self::Error> {
+                serde::Serializer::$method(self.ser, $($arg),*)
+            }
+        )*
+    };
+}
+
+/// Serializes the value of a field in [`StructEncoding::Presence`]. An option at the top clears
+/// the presence bit when it is `None` and is written without a marker when it is `Some`.
+#[cfg(feature = "alloc")]
+struct FieldSerializer<'a, W: Write> {
+    ser: &'a mut Serializer<W>,
+    present: &'a mut bool,
+}
+
+#[cfg(feature = "alloc")]
+impl<'a, W: Write> serde::Serializer for FieldSerializer<'a, W>
+{
+    type Ok = ();
+    type Error = Error;
+
+    type SerializeSeq = Compound<'a, W>;
+    type SerializeTuple = &'a mut Serializer<W>;
+    type SerializeTupleStruct = &'a mut Serializer<W>;
+    type SerializeTupleVariant = &'a mut Serializer<W>;
+    type SerializeMap = Compound<'a, W>;
+    type SerializeStruct = Compound<'a, W>;
+    type SerializeStructVariant = Compound<'a, W>;
+
+    fn serialize_none(self) -> Result<Self::Ok, Self::Error> {
+        *self.present = false;
+        Ok(())
+    }
+
+    fn serialize_some<T: ?Sized + Serialize>(self, value: &T) -> Result<Self::Ok, Self::Error> {
+        value.serialize(self.ser)
+    }
+
+    fn serialize_newtype_struct<T: ?Sized + Serialize>(self, name: &'static str, value: &T) -> Result<Self::Ok, Self::Error> {
+        self.ser.serialize_newtype_struct(name, value)
+    }
+
+    fn serialize_newtype_variant<T: ?Sized + Serialize>(self, name: &'static str, variant_index: u32, variant: &'static str, value: &T) -> Result<Self::Ok, Self::Error> {
+        self.ser.serialize_newtype_variant(name, variant_index, variant, value)
+    }
+
+    forward_to_ser! {
+        serialize_bool(v: bool) -> ();
+        serialize_i8(v: i8) -> ();
+        serialize_i16(v: i16) -> ();
+        serialize_i32(v: i32) -> ();
+        serialize_i64(v: i64) -> ();
+        serialize_i128(v: i128) -> ();
+        serialize_u8(v: u8) -> ();
+        serialize_u16(v: u16) -> ();
+        serialize_u32(v: u32) -> ();
+        serialize_u64(v: u64) -> ();
+        serialize_u128(v: u128) -> ();
+        serialize_f32(v: f32) -> ();
+        serialize_f64(v: f64) -> ();
+        serialize_char(v: char) -> ();
+        serialize_str(v: &str) -> ();
+        serialize_bytes(v: &[u8]) -> ();
+        serialize_unit() -> ();
+        serialize_unit_struct(name: &'static str) -> ();
+        serialize_unit_variant(name: &'static str, variant_index: u32, variant: &'static str) -> ();
+        serialize_seq(len: Option<usize>) -> Self::SerializeSeq;
+        serialize_tuple(len: usize) -> Self::SerializeTuple;
+        serialize_tuple_struct(name: &'static str, len: usize) -> Self::SerializeTupleStruct;
+        serialize_tuple_variant(name: &'static str, variant_index: u32, variant: &'static str, len: usize) -> Self::SerializeTupleVariant;
+        serialize_map(len: Option<usize>) -> Self::SerializeMap;
+        serialize_struct(name: &'static str, len: usize) -> Self::SerializeStruct;
+        serialize_struct_variant(name: &'static str, variant_index: u32, variant: &'static str, len: usize) -> Self::SerializeStructVariant;
+    }
+}
+
+pub fn from_bytes<'de, T>(bytes: &'de [u8]) -> Result<T, Error>
+where
+    T: serde::Deserialize<'de>,
+{
+    Config::default().deserialize_from_bytes(bytes)
+}
+
+pub fn take_from_bytes<'de, T>(bytes: &'de [u8]) -> Result<(T, &'de [u8]), Error>
+where
+    T: serde::Deserialize<'de>,
+{
+    Config::default().take_from_bytes(bytes)
+}
+
+#[cfg(feature = "std")]
+pub fn from_reader<T, R>(reader: R) -> Result<T, Error>
+where
+    T: serde::de::DeserializeOwned,
+    R: std::io::Read,
+{
+    Config::default().deserialize_from_reader(reader)
+}
+
+/// A run of bytes that either points into the input or had to be copied out of a stream.
+pub enum Bytes<'de>
+{
+    Borrowed(&'de [u8]),
+    #[cfg(feature = "alloc")]
+    Owned(Vec<u8>),
+}
+
+/// Where the [`Deserializer`] pulls its bytes from.
+///
+/// Implemented by [`SliceReader`] for in-memory buffers and, with the `std` feature, [`IoReader`]
+/// for streams.
+pub trait Read<'de>
+{
+    /// Fill `buf` completely or fail.
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), Error>;
+
+    /// Take the next `len` bytes, borrowing them from the input when possible.
+    fn read_bytes(&mut self, len: usize) -> Result<Bytes<'de>, Error>;
+
+    /// Look at the next byte without consuming it, `None` at the end of the input.
+    fn peek(&mut self) -> Result<Option<u8>, Error>;
+
+    /// Number of bytes consumed so far.
+    fn offset(&self) -> u64;
+
+    /// Whether the end of the input can be found without waiting for more of it, which makes
+    /// [`TrailingBytes::Reject`] the default. False unless overridden.
+    fn is_bounded(&self) -> bool {
+        false
+    }
+}
+
+/// Reads from an in-memory buffer that strings and byte arrays can borrow from.
+pub struct SliceReader<'de>
+{
+    bytes: &'de [u8],
+    offset: usize,
+}
+
+impl<'de> SliceReader<'de>
+{
+    pub fn new(bytes: &'de [u8]) -> Self {
+        Self {
+            bytes,
+            offset: 0,
+        }
+    }
+
+    /// The input not consumed yet.
+    pub fn remaining(&self) -> &'de [u8] {
+        self.bytes
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'de [u8], Error> {
+        if self.bytes.len() < len {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "unexpected end of input").at(self.offset()));
+        }
+        let (head, tail) = self.bytes.split_at(len);
+        self.bytes = tail;
+        self.offset += len;
+        Ok(head)
+    }
+}
+
+impl<'de> Read<'de> for SliceReader<'de>
+{
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), Error> {
+        buf.copy_from_slice(self.take(buf.len())?);
+        Ok(())
+    }
+
+    fn read_bytes(&mut self, len: usize) -> Result<Bytes<'de>, Error> {
+        self.take(len).map(Bytes::Borrowed)
+    }
+
+    fn is_bounded(&self) -> bool {
+        true
+    }
+
+    fn peek(&mut self) -> Result<Option<u8>, Error> {
+        Ok(self.bytes.first().copied())
+    }
+
+    fn offset(&self) -> u64 {
+        self.offset as u64
+    }
+}
+
+/// Reads from a stream, everything read from it is copied.
+#[cfg(feature = "std")]
+pub struct IoReader<R: std::io::Read>
+{
+    reader: R,
+    offset: u64,
+    peeked: Option<u8>,
+}
+
+#[cfg(feature = "std")]
+impl<R: std::io::Read> IoReader<R>
+{
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            offset: 0,
+            peeked: None,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<'de, R: std::io::Read> Read<'de> for IoReader<R>
+{
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), Error> {
+        let len = buf.len();
+        let rest = match (self.peeked.take(), buf.split_first_mut()) {
+            (Some(byte), Some((first, rest))) => {
+                *first = byte;
+                rest
+            }
+            (peeked, _) => {
+                self.peeked = peeked;
+                buf
+            }
+        };
+        self.reader.read_exact(rest).map_err(|err| Error::io(err).at(self.offset))?;
+        self.offset += len as u64;
+        Ok(())
+    }
+
+    fn read_bytes(&mut self, len: usize) -> Result<Bytes<'de>, Error> {
+        // Grow the buffer as data actually arrives rather than trusting `len` up front.
+        let mut buf = Vec::new();
+        let rest = match self.peeked.take() {
+            Some(byte) if len > 0 => {
+                buf.push(byte);
+                len - 1
+            }
+            peeked => {
+                self.peeked = peeked;
+                len
+            }
+        };
+        let read = (&mut self.reader).take(rest as u64).read_to_end(&mut buf);
+        read.map_err(|err| Error::io(err).at(self.offset))?;
+        if buf.len() < len {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "unexpected end of input").at(self.offset + buf.len() as u64));
+        }
+        self.offset += len as u64;
+        Ok(Bytes::Owned(buf))
+    }
+
+    fn peek(&mut self) -> Result<Option<u8>, Error> {
+        if self.peeked.is_none() {
+            let mut byte = [0; 1];
+            loop {
+                match self.reader.read(&mut byte) {
+                    Ok(0) => return Ok(None),
+                    Ok(_) => break,
+                    Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+                    Err(err) => return Err(Error::io(err).at(self.offset)),
+                }
+            }
+            self.peeked = Some(byte[0]);
+        }
+        Ok(self.peeked)
+    }
+
+    fn offset(&self) -> u64 {
+        self.offset
+    }
+}
+
+pub struct Deserializer<R>
+{
+    reader: R,
+    config: Config,
+    depth: usize,
+    /// Offset of the top-level value being decoded, the byte limit counts from here.
+    start: u64,
+    /// CRC32C of the top-level value read so far, when checksums are on.
+    crc: u32,
+    /// Copy of the bytes of the canonical map key being read.
+    #[cfg(feature = "alloc")]
+    key: Option<Vec<u8>>,
+}
+
+#[cfg(feature = "std")]
+impl<R: std::io::Read> Deserializer<IoReader<R>>
+{
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader: IoReader::new(reader),
+            config: Config::default(),
+            depth: 0,
+            start: 0,
+            crc: 0,
+            #[cfg(feature = "alloc")]
+            key: None,
+        }
+    }
+}
+
+impl<'de> Deserializer<SliceReader<'de>>
+{
+    pub fn from_slice(bytes: &'de [u8]) -> Self {
+        Self {
+            reader: SliceReader::new(bytes),
+            config: Config::default(),
+            depth: 0,
+            start: 0,
+            crc: 0,
+            #[cfg(feature = "alloc")]
+            key: None,
+        }
+    }
+
+    /// The input not consumed yet.
+    pub fn remaining(&self) -> &'de [u8] {
+        self.reader.remaining()
+    }
+
+    /// When the rest of the input has to be exactly one value, check its checksum trailer before
+    /// decoding, so that a damaged length cannot send the decoder astray.
+    fn verify_checksum_upfront(&self) -> Result<(), Error> {
+        let bytes = self.remaining();
+        if !self.config.checksum || self.trailing_bytes() == TrailingBytes::Allow || bytes.len() < 4 {
+            return Ok(());
+        }
+        let (value, trailer) = bytes.split_at(bytes.len() - 4);
+        let expected = u32::from_be_bytes(trailer.try_into().unwrap());
+        let actual = checksum::crc32c(value);
+        if expected != actual {
+            return Err(Error::new(ErrorKind::ChecksumMismatch, format_args!("checksum {:#010x} does not match the {:#010x} of the value", expected, actual)).at(self.offset()));
+        }
+        Ok(())
+    }
+}
+
+impl<R> Deserializer<R>
+{
+    pub fn with_config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+}
+
+impl<'de, R: Read<'de>> Deserializer<R>
+{
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), Error> {
+        self.check_budget(buf.len())?;
+        self.reader.read_exact(buf)?;
+        self.consumed(buf);
+        Ok(())
+    }
+
+    fn read_bytes(&mut self, len: usize) -> Result<Bytes<'de>, Error> {
+        if let Some(limit) = self.config.max_alloc {
+            if len > limit {
+                return Err(Error::new(ErrorKind::LimitExceeded, format_args!("length {} exceeds the allocation limit of {} bytes", len, limit)).at(self.offset()));
+            }
+        }
+        self.check_budget(len)?;
+        let bytes = self.reader.read_bytes(len)?;
+        match &bytes {
+            Bytes::Borrowed(bytes) => self.consumed(bytes),
+            #[cfg(feature = "alloc")]
+            Bytes::Owned(bytes) => self.consumed(bytes),
+        }
+        Ok(bytes)
+    }
+
+    /// Account for bytes taken from the input.
+    fn consumed(&mut self, bytes: &[u8]) {
+        if self.config.checksum {
+            self.crc = checksum::crc32c_append(self.crc, bytes);
+        }
+        #[cfg(feature = "alloc")]
+        if let Some(key) = &mut self.key {
+            key.extend_from_slice(bytes);
+        }
+    }
+
+    /// In canonical self-describing mode, fail unless the next value carries one of `tags`, so that
+    /// a value cannot be read from the tag of a narrower or related type. An empty list accepts
+    /// any tag.
+    fn expect_tag(&mut self, tags: &[u8]) -> Result<(), Error> {
+        if !self.config.canonical || tags.is_empty() {
+            return Ok(());
+        }
+        let offset = self.offset();
+        match self.reader.peek()? {
+            Some(tag) if !tags.contains(&tag) => Err(self.non_canonical(format_args!("type tag 0x{:02x} for this type", tag), offset)),
+            _ => Ok(()),
+        }
+    }
+
+    fn non_canonical(&self, what: impl core::fmt::Display, offset: u64) -> Error {
+        Error::new(ErrorKind::NonCanonical, format_args!("{} is not in canonical form", what)).at(offset)
+    }
+
+    fn read_f32(&mut self) -> Result<f32, Error> {
+        let offset = self.offset();
+        let v: f32 = self.read_fixed()?;
+        if self.config.canonical && v.to_bits() != canonical_f32(v).to_bits() {
+            return Err(self.non_canonical("float", offset));
+        }
+        Ok(v)
+    }
+
+    fn read_f64(&mut self) -> Result<f64, Error> {
+        let offset = self.offset();
+        let v: f64 = self.read_fixed()?;
+        if self.config.canonical && v.to_bits() != canonical_f64(v).to_bits() {
+            return Err(self.non_canonical("float", offset));
+        }
+        Ok(v)
+    }
+
+    /// Read the checksum trailer of the top-level value just decoded, if [`Config::checksum`] is
+    /// on, and compare it with the bytes of the value.
+    fn verify_checksum(&mut self) -> Result<(), Error> {
+        if !self.config.checksum {
+            return Ok(());
+        }
+        let actual = self.crc;
+        let mut trailer = [0; 4];
+        self.read_exact(&mut trailer)?;
+        self.crc = 0;
+        let expected = u32::from_be_bytes(trailer);
+        if expected != actual {
+            return Err(Error::new(ErrorKind::ChecksumMismatch, format_args!("checksum {:#010x} does not match the {:#010x} of the value", expected, actual)).at(self.start));
+        }
         Ok(())
     }
-}
 
-impl<'a> serde::ser::SerializeMap for &'a mut Serializer
-{
-    type Ok = ();
-    type Error = Error;
+    /// Fail before reading `len` more bytes would go over the byte limit.
+    fn check_budget(&self, len: usize) -> Result<(), Error> {
+        match self.config.byte_limit {
+            Some(limit) if (self.offset() - self.start).saturating_add(len as u64) > limit => {
+                Err(Error::new(ErrorKind::LimitExceeded, format_args!("input exceeds the byte limit of {} bytes", limit)).at(self.offset()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Read the element count of a sequence or map, `None` if it was not known to the writer.
+    fn read_count(&mut self) -> Result<Option<usize>, Error> {
+        let offset = self.offset();
+        let len = self.read_len_raw()?;
+        if len == self.config.length_width.max() {
+            if self.config.canonical {
+                return Err(self.non_canonical("length of unknown size", offset));
+            }
+            return Ok(None);
+        }
+        let len = usize::try_from(len).map_err(|_| Error::new(ErrorKind::LengthOverflow, "length does not fit in memory").at(offset))?;
+        match self.config.max_len {
+            Some(limit) if len > limit => {
+                Err(Error::new(ErrorKind::LimitExceeded, format_args!("length {} exceeds the limit of {} elements", len, limit)).at(offset))
+            }
+            _ => Ok(Some(len)),
+        }
+    }
+
+    /// Run `f` one level deeper, failing once the depth limit is reached.
+    fn nested<T>(&mut self, f: impl FnOnce(&mut Self) -> Result<T, Error>) -> Result<T, Error> {
+        if self.depth >= self.config.max_depth {
+            return Err(Error::new(ErrorKind::LimitExceeded, format_args!("nesting exceeds the depth limit of {}", self.config.max_depth)).at(self.offset()));
+        }
+        self.depth += 1;
+        let result = f(self);
+        self.depth -= 1;
+        result
+    }
+
+    fn read_array<const N: usize>(&mut self) -> Result<[u8; N], Error> {
+        let mut bytes = [0; N];
+        self.read_exact(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    fn read_u8(&mut self) -> Result<u8, Error> {
+        let [byte] = self.read_array()?;
+        Ok(byte)
+    }
+
+    fn read_len(&mut self) -> Result<usize, Error> {
+        let offset = self.offset();
+        let len = self.read_len_raw()?;
+        usize::try_from(len).map_err(|_| Error::new(ErrorKind::LengthOverflow, "length does not fit in memory").at(offset))
+    }
+
+    fn read_len_raw(&mut self) -> Result<u64, Error> {
+        Ok(match self.config.length_width {
+            LengthWidth::U8 => self.read_unsigned::<u8>()? as u64,
+            LengthWidth::U16 => self.read_unsigned::<u16>()? as u64,
+            LengthWidth::U32 => self.read_unsigned::<u32>()? as u64,
+            LengthWidth::U64 => self.read_unsigned::<u64>()?,
+        })
+    }
+
+    fn read_fixed<T: FixedWidth>(&mut self) -> Result<T, Error> {
+        let mut bytes = T::Bytes::default();
+        self.read_exact(bytes.as_mut())?;
+        Ok(T::from_bytes(bytes, self.config.endian))
+    }
+
+    fn read_unsigned<T: FixedWidth + TryFrom<u128>>(&mut self) -> Result<T, Error> {
+        match self.config.int_encoding {
+            IntEncoding::Fixed => self.read_fixed(),
+            IntEncoding::Varint => {
+                let offset = self.offset();
+                let v = self.read_varint()?;
+                T::try_from(v).map_err(|_| Error::new(ErrorKind::IntegerOverflow, "integer out of range").at(offset))
+            }
+        }
+    }
+
+    fn read_signed<T: FixedWidth + TryFrom<i128>>(&mut self) -> Result<T, Error> {
+        match self.config.int_encoding {
+            IntEncoding::Fixed => self.read_fixed(),
+            IntEncoding::Varint => {
+                let offset = self.offset();
+                let zigzag = self.read_varint()?;
+                let v = (zigzag >> 1) as i128 ^ -((zigzag & 1) as i128);
+                T::try_from(v).map_err(|_| Error::new(ErrorKind::IntegerOverflow, "integer out of range").at(offset))
+            }
+        }
+    }
+
+    fn read_varint(&mut self) -> Result<u128, Error> {
+        let offset = self.offset();
+        let mut v: u128 = 0;
+        let mut shift = 0;
+        loop {
+            let byte = self.read_u8()?;
+            let part = (byte & 0x7f) as u128;
+            if shift >= 128 || (part << shift) >> shift != part {
+                return Err(Error::new(ErrorKind::IntegerOverflow, "varint is too long").at(offset));
+            }
+            v |= part << shift;
+            if byte & 0x80 == 0 {
+                if self.config.canonical && byte == 0 && shift > 0 {
+                    return Err(self.non_canonical("overlong varint", offset));
+                }
+                return Ok(v);
+            }
+            shift += 7;
+        }
+    }
 
-    fn serialize_key<T: ?Sized>(&mut self, key: &T) -> Result<(), Self::Error>
-    where
-        T: serde::Serialize,
-    {
-        key.serialize(&mut **self)
+    /// Byte offset of the next unread byte.
+    pub fn offset(&self) -> u64 {
+        self.reader.offset()
     }
 
-    fn serialize_value<T: ?Sized>(&mut self, value: &T) -> Result<(), Self::Error>
-    where
-        T: serde::Serialize,
-    {
-        value.serialize(&mut **self)
+    /// Verify the checksum trailer if there is one, then check that the input is exhausted, as the
+    /// trailing bytes policy requires.
+    pub fn end(&mut self) -> Result<(), Error> {
+        self.verify_checksum()?;
+        if self.trailing_bytes() == TrailingBytes::Allow {
+            return Ok(());
+        }
+        match self.reader.peek()? {
+            Some(_) => Err(Error::new(ErrorKind::TrailingData, "trailing bytes after the value").at(self.offset())),
+            None => Ok(()),
+        }
     }
 
-    fn end(self) -> Result<Self::Ok, Self::Error> {
-        Ok(())
+    fn trailing_bytes(&self) -> TrailingBytes {
+        match self.config.trailing_bytes {
+            Some(policy) => policy,
+            None if self.reader.is_bounded() => TrailingBytes::Reject,
+            None => TrailingBytes::Allow,
+        }
     }
-}
 
-impl<'a> serde::ser::SerializeStruct for &'a mut Serializer
-{
-    type Ok = ();
-    type Error = Error;
+    /// Deserialize one top-level value and apply the trailing bytes policy.
+    fn deserialize_value<T: serde::Deserialize<'de>>(&mut self) -> Result<T, Error> {
+        let value = T::deserialize(&mut *self).map_err(|err| self.fix_position(err))?;
+        self.end()?;
+        Ok(value)
+    }
 
-    fn serialize_field<T: ?Sized>(&mut self, _key: &'static str, value: &T) -> Result<(), Self::Error>
-    where
-        T: serde::Serialize,
-    {
-        value.serialize(&mut **self)
+    /// Decode one value after another until the input ends, see [`StreamDeserializer`]. This is not
+    /// `IntoIterator`, whose items could not be chosen per call.
+    pub fn into_stream<T: serde::Deserialize<'de>>(self) -> StreamDeserializer<'de, R, T> {
+        StreamDeserializer {
+            de: self,
+            item_offset: None,
+            failed: false,
+            marker: core::marker::PhantomData,
+        }
     }
 
-    fn end(self) -> Result<Self::Ok, Self::Error> {
-        Ok(())
+    /// Attach the current offset to errors raised by visitors, which cannot know it.
+    fn fix_position(&self, err: Error) -> Error {
+        err.at(self.offset())
     }
-}
 
-impl<'a> serde::ser::SerializeStructVariant for &'a mut Serializer
-{
-    type Ok = ();
-    type Error = Error;
+    fn visit_char_body<V: serde::de::Visitor<'de>>(&mut self, visitor: V) -> Result<V::Value, Error> {
+        let len = self.read_u8()? as usize;
+        let offset = self.offset();
+        if self.config.canonical && len > 4 {
+            return Err(self.non_canonical("char length", offset));
+        }
+        let mut buf = [0; 4];
+        let bytes = buf.get_mut(..len).ok_or_else(|| Error::new(ErrorKind::InvalidUtf8, "char is longer than 4 bytes").at(offset))?;
+        self.read_exact(bytes)?;
+        let s = core::str::from_utf8(bytes).map_err(|_| Error::new(ErrorKind::InvalidUtf8, "invalid utf-8").at(offset))?;
+        let c = s.chars().next().ok_or(Error::new(ErrorKind::InvalidUtf8, "empty string").at(offset))?;
+        // Lenient decoders take the first char of whatever string they find.
+        if self.config.canonical && c.len_utf8() != len {
+            return Err(self.non_canonical("char", offset));
+        }
+        visitor.visit_char(c)
+    }
 
-    fn serialize_field<T: ?Sized>(&mut self, _key: &'static str, value: &T) -> Result<(), Self::Error>
-    where
-        T: serde::Serialize,
-    {
-        value.serialize(&mut **self)
+    fn visit_str_body<V: serde::de::Visitor<'de>>(&mut self, visitor: V) -> Result<V::Value, Error> {
+        let len = self.read_len()?;
+        let offset = self.offset();
+        match self.read_bytes(len)? {
+            Bytes::Borrowed(bytes) => {
+                let s = core::str::from_utf8(bytes).map_err(|_| Error::new(ErrorKind::InvalidUtf8, "invalid utf-8").at(offset))?;
+                visitor.visit_borrowed_str(s)
+            }
+            #[cfg(feature = "alloc")]
+            Bytes::Owned(bytes) => {
+                let s = String::from_utf8(bytes).map_err(|_| Error::new(ErrorKind::InvalidUtf8, "invalid utf-8").at(offset))?;
+                visitor.visit_string(s)
+            }
+        }
     }
 
-    fn end(self) -> Result<Self::Ok, Self::Error> {
-        Ok(())
+    fn visit_bytes_body<V: serde::de::Visitor<'de>>(&mut self, visitor: V) -> Result<V::Value, Error> {
+        let len = self.read_len()?;
+        match self.read_bytes(len)? {
+            Bytes::Borrowed(bytes) => visitor.visit_borrowed_bytes(bytes),
+            #[cfg(feature = "alloc")]
+            Bytes::Owned(bytes) => visitor.visit_byte_buf(bytes),
+        }
     }
 }
 
-pub fn from_bytes<'de, T>(bytes: &'de [u8]) -> Result<T, Error>
-where
-    T: serde::de::DeserializeOwned,
+/// An iterator over values written back to back, as by repeated calls to `to_writer`, made by
+/// [`Deserializer::into_stream`].
+///
+/// Iteration ends without an error when the input runs out between two values. Input that ends
+/// in the middle of a value yields an [`ErrorKind::UnexpectedEof`] error instead. After any error
+/// the position in the input is lost and the iterator ends.
+pub struct StreamDeserializer<'de, R, T>
 {
-    let boxed: Box<dyn std::io::Read + 'de> = Box::new(bytes);
-    let mut deserializer = Deserializer::new(boxed);
-    T::deserialize(&mut deserializer)
+    de: Deserializer<R>,
+    item_offset: Option<u64>,
+    failed: bool,
+    marker: core::marker::PhantomData<(&'de (), T)>,
 }
 
-pub fn from_reader<'de, T, De>(reader: De) -> Result<T, Error>
-where
-    T: serde::de::DeserializeOwned,
-    De: std::io::Read + 'de,
+impl<'de, R: Read<'de>, T: serde::Deserialize<'de>> StreamDeserializer<'de, R, T>
 {
-    let boxed: Box<dyn std::io::Read + 'de> = Box::new(reader);
-    let mut deserializer = Deserializer::new(boxed);
-    T::deserialize(&mut deserializer)
-}
+    /// Byte offset at which the value returned last started, `None` before the first one.
+    pub fn item_offset(&self) -> Option<u64> {
+        self.item_offset
+    }
 
-pub struct Deserializer<'de>
-{
-    reader: Box<dyn std::io::Read + 'de>,
+    /// Byte offset of the next unread byte, where the next value starts.
+    pub fn offset(&self) -> u64 {
+        self.de.offset()
+    }
 }
 
-impl<'de> Deserializer<'de>
+impl<'de, R: Read<'de>, T: serde::Deserialize<'de>> Iterator for StreamDeserializer<'de, R, T>
 {
-    pub fn new(reader: Box<dyn std::io::Read + 'de>) -> Self {
-        Self {
-            reader,
+    type Item = Result<T, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
         }
+        let result = match self.de.reader.peek() {
+            Ok(None) => return None,
+            Ok(Some(_)) => {
+                let offset = self.de.offset();
+                self.item_offset = Some(offset);
+                self.de.start = offset;
+                T::deserialize(&mut self.de).map_err(|err| self.de.fix_position(err)).and_then(|value| {
+                    self.de.verify_checksum()?;
+                    Ok(value)
+                })
+            }
+            Err(err) => Err(err),
+        };
+        self.failed = result.is_err();
+        Some(result)
     }
 }
 
-impl<'de> std::io::Read for &mut Deserializer<'de>
-{
-    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        self.reader.read(buf)
-    }
+/// In self-describing mode the tag in the input decides what gets visited, not the requested type.
+/// The tags listed are the ones the requested type is written with, which canonical input must use.
+macro_rules! self_described {
+    ($de:ident, $visitor:ident $(, $tag:expr)*) => {
+        if $de.config.self_describing {
+            $de.expect_tag(&[$($tag),*])?;
+            return $de.deserialize_any($visitor);
+        }
+    };
 }
 
-impl<'de> serde::Deserializer<'de> for &'de mut Deserializer<'de> {
+impl<'de, R: Read<'de>> serde::Deserializer<'de> for &mut Deserializer<R> {
     type Error = Error;
 
     fn deserialize_any<V: serde::de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
-        Err(Error::custom("not implemented"))
+        if !self.config.self_describing {
+            return Err(Error::new(ErrorKind::Unsupported, "deserialize_any requires self-describing mode").at(self.offset()));
+        }
+        let offset = self.offset();
+        match self.read_u8()? {
+            TRUE => visitor.visit_bool(true),
+            FALSE => visitor.visit_bool(false),
+            NONE => visitor.visit_none(),
+            SOME => self.nested(|de| visitor.visit_some(de)),
+            TAG_UNIT => visitor.visit_unit(),
+            TAG_U8 => visitor.visit_u8(self.read_u8()?),
+            TAG_U16 => visitor.visit_u16(self.read_unsigned()?),
+            TAG_U32 => visitor.visit_u32(self.read_unsigned()?),
+            TAG_U64 => visitor.visit_u64(self.read_unsigned()?),
+            TAG_U128 => visitor.visit_u128(self.read_unsigned()?),
+            TAG_I8 => visitor.visit_i8(self.read_fixed()?),
+            TAG_I16 => visitor.visit_i16(self.read_signed()?),
+            TAG_I32 => visitor.visit_i32(self.read_signed()?),
+            TAG_I64 => visitor.visit_i64(self.read_signed()?),
+            TAG_I128 => visitor.visit_i128(self.read_signed()?),
+            TAG_F32 => visitor.visit_f32(self.read_f32()?),
+            TAG_F64 => visitor.visit_f64(self.read_f64()?),
+            TAG_CHAR => self.visit_char_body(visitor),
+            TAG_STR => self.visit_str_body(visitor),
+            TAG_BYTES => self.visit_bytes_body(visitor),
+            TAG_SEQ => {
+                let len = self.read_count()?;
+                self.nested(|de| visitor.visit_seq(Walk::counted(de, len)))
+            }
+            TAG_SEQ_UNBOUNDED | TAG_MAP_UNBOUNDED if self.config.canonical => Err(self.non_canonical("sequence or map of unknown length", offset)),
+            TAG_SEQ_UNBOUNDED => self.nested(|de| visitor.visit_seq(Walk::counted(de, None))),
+            TAG_MAP => {
+                let len = self.read_count()?;
+                self.nested(|de| visitor.visit_map(Walk::counted(de, len)))
+            }
+            TAG_MAP_UNBOUNDED => self.nested(|de| visitor.visit_map(Walk::counted(de, None))),
+            TAG_VARIANT => {
+                // Without a type to guide it a variant reads like `{ "name": payload }`.
+                let _variant_index = self.read_unsigned::<u32>()?;
+                let len = self.read_len()?;
+                let name = self.read_bytes(len)?;
+                self.nested(|de| visitor.visit_map(VariantMap { de, name: Some(name) }))
+            }
+            tag => Err(Error::new(ErrorKind::InvalidTag, format_args!("invalid type tag 0x{:02x}", tag)).at(offset)),
+        }
     }
 
     fn deserialize_bool<V: serde::de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
-        // Get one byte from self.reader
-        let mut bytes = [0; 1];
-        self.reader.read_exact(&mut bytes).map_err(|_| Error::custom("failed to read"))?;
-        visitor.visit_bool(match bytes[0] {
+        self_described!(self, visitor, TRUE, FALSE);
+        // Get one byte from the input
+        let offset = self.offset();
+        visitor.visit_bool(match self.read_u8()? {
             TRUE => true,
             FALSE => false,
-            _ => return Err(Error::custom("invalid boolean value")),
+            _ => return Err(Error::new(ErrorKind::InvalidTag, "invalid boolean value").at(offset)),
         })
     }
 
     fn deserialize_i8<V: serde::de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
-        let mut bytes = [0; 1];
-        self.reader.read_exact(&mut bytes).map_err(|_| Error::custom("failed to read"))?;
-        visitor.visit_i8(i8::from_be_bytes(bytes))
+        self_described!(self, visitor, TAG_I8);
+        visitor.visit_i8(self.read_fixed()?)
     }
 
     fn deserialize_i16<V: serde::de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
-        let mut bytes = [0; 2];
-        self.reader.read_exact(&mut bytes).map_err(|_| Error::custom("failed to read"))?;
-        visitor.visit_i16(i16::from_be_bytes(bytes))
+        self_described!(self, visitor, TAG_I16);
+        visitor.visit_i16(self.read_signed()?)
     }
 
     fn deserialize_i32<V: serde::de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
-        let mut bytes = [0; 4];
-        self.reader.read_exact(&mut bytes).map_err(|_| Error::custom("failed to read"))?;
-        visitor.visit_i32(i32::from_be_bytes(bytes))
+        self_described!(self, visitor, TAG_I32);
+        visitor.visit_i32(self.read_signed()?)
     }
 
     fn deserialize_i64<V: serde::de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
-        let mut bytes = [0; 8];
-        self.reader.read_exact(&mut bytes).map_err(|_| Error::custom("failed to read"))?;
-        visitor.visit_i64(i64::from_be_bytes(bytes))
+        self_described!(self, visitor, TAG_I64);
+        visitor.visit_i64(self.read_signed()?)
+    }
+
+    fn deserialize_i128<V: serde::de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        self_described!(self, visitor, TAG_I128);
+        visitor.visit_i128(self.read_signed()?)
     }
 
     fn deserialize_u8<V: serde::de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
-        let mut bytes = [0; 1];
-        self.reader.read_exact(&mut bytes).map_err(|_| Error::custom("failed to read"))?;
-        visitor.visit_u8(bytes[0])
+        self_described!(self, visitor, TAG_U8);
+        visitor.visit_u8(self.read_u8()?)
     }
 
     fn deserialize_u16<V: serde::de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
-        let mut bytes = [0; 2];
-        self.reader.read_exact(&mut bytes).map_err(|_| Error::custom("failed to read"))?;
-        visitor.visit_u16(u16::from_be_bytes(bytes))
+        self_described!(self, visitor, TAG_U16);
+        visitor.visit_u16(self.read_unsigned()?)
     }
 
     fn deserialize_u32<V: serde::de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
-        let mut bytes = [0; 4];
-        self.reader.read_exact(&mut bytes).map_err(|_| Error::custom("failed to read"))?;
-        visitor.visit_u32(u32::from_be_bytes(bytes))
+        self_described!(self, visitor, TAG_U32);
+        visitor.visit_u32(self.read_unsigned()?)
     }
 
     fn deserialize_u64<V: serde::de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
-        let mut bytes = [0; 8];
-        self.reader.read_exact(&mut bytes).map_err(|_| Error::custom("failed to read"))?;
-        visitor.visit_u64(u64::from_be_bytes(bytes))
+        self_described!(self, visitor, TAG_U64);
+        visitor.visit_u64(self.read_unsigned()?)
+    }
+
+    fn deserialize_u128<V: serde::de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        self_described!(self, visitor, TAG_U128);
+        visitor.visit_u128(self.read_unsigned()?)
     }
 
     fn deserialize_f32<V: serde::de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
-        let mut bytes = [0; 4];
-        self.reader.read_exact(&mut bytes).map_err(|_| Error::custom("failed to read"))?;
-        visitor.visit_f32(f32::from_be_bytes(bytes))
+        self_described!(self, visitor, TAG_F32);
+        visitor.visit_f32(self.read_f32()?)
     }
 
     fn deserialize_f64<V: serde::de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
-        let mut bytes = [0; 8];
-        self.reader.read_exact(&mut bytes).map_err(|_| Error::custom("failed to read"))?;
-        visitor.visit_f64(f64::from_be_bytes(bytes))
+        self_described!(self, visitor, TAG_F64);
+        visitor.visit_f64(self.read_f64()?)
     }
 
     fn deserialize_char<V: serde::de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
-        let len: u8 = from_reader(&mut *self)?;
-        let mut bytes = vec![0; len as usize];
-        self.reader.read_exact(&mut bytes).map_err(|_| Error::custom("failed to read"))?;
-        let s = String::from_utf8(bytes).map_err(|_| Error::custom("invalid utf-8"))?;
-        let c = s.chars().next().ok_or(Error::custom("empty string"))?;
-        visitor.visit_char(c)
+        self_described!(self, visitor, TAG_CHAR);
+        self.visit_char_body(visitor)
     }
 
     fn deserialize_str<V: serde::de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
-        let len: u32 = from_reader(&mut *self)?;
-        let mut bytes = vec![0; len as usize];
-        self.reader.read_exact(&mut bytes).map_err(|_| Error::custom("failed to read"))?;
-        let s = String::from_utf8(bytes).map_err(|_| Error::custom("invalid utf-8"))?;
-        visitor.visit_str(&s)
+        self_described!(self, visitor, TAG_STR);
+        self.visit_str_body(visitor)
     }
 
     fn deserialize_string<V: serde::de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
@@ -505,10 +2250,8 @@ impl<'de> serde::Deserializer<'de> for &'de mut Deserializer<'de> {
     }
 
     fn deserialize_bytes<V: serde::de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
-        let len: u32 = from_reader(&mut *self)?;
-        let mut bytes = vec![0; len as usize];
-        self.reader.read_exact(&mut bytes).map_err(|_| Error::custom("failed to read"))?;
-        visitor.visit_bytes(&bytes)
+        self_described!(self, visitor, TAG_BYTES);
+        self.visit_bytes_body(visitor)
     }
 
     fn deserialize_byte_buf<V: serde::de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
@@ -516,76 +2259,218 @@ impl<'de> serde::Deserializer<'de> for &'de mut Deserializer<'de> {
     }
 
     fn deserialize_option<V: serde::de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
-        let b: u8 = from_reader(&mut *self)?;
-        match b {
+        self_described!(self, visitor, NONE, SOME);
+        let offset = self.offset();
+        match self.read_u8()? {
             NONE => visitor.visit_none(),
-            SOME => visitor.visit_some(self),
-            _ => Err(Error::custom("invalid option value")),
+            SOME => self.nested(|de| visitor.visit_some(de)),
+            _ => Err(Error::new(ErrorKind::InvalidTag, "invalid option value").at(offset)),
         }
     }
 
     fn deserialize_unit<V: serde::de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        self_described!(self, visitor, TAG_UNIT);
         visitor.visit_unit()
     }
 
     fn deserialize_unit_struct<V: serde::de::Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value, Self::Error> {
-        visitor.visit_unit()
+        self.deserialize_unit(visitor)
     }
 
     fn deserialize_newtype_struct<V: serde::de::Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value, Self::Error> {
-        visitor.visit_newtype_struct(self)
+        self.nested(|de| visitor.visit_newtype_struct(de))
     }
 
     fn deserialize_seq<V: serde::de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
-        let len: u32 = from_reader(&mut *self)?;
-        visitor.visit_seq(Walk { de: self, len: len as usize })
+        self_described!(self, visitor, TAG_SEQ);
+        let len = self.read_count()?;
+        self.nested(|de| visitor.visit_seq(Walk::counted(de, len)))
     }
 
     fn deserialize_tuple<V: serde::de::Visitor<'de>>(self, len: usize, visitor: V) -> Result<V::Value, Self::Error> {
-        visitor.visit_seq(Walk { de: self, len })
+        self_described!(self, visitor, TAG_SEQ);
+        self.nested(|de| visitor.visit_seq(Walk::new(de, len)))
     }
 
     fn deserialize_tuple_struct<V: serde::de::Visitor<'de>>(self, _name: &'static str, len: usize, visitor: V) -> Result<V::Value, Self::Error> {
-        visitor.visit_seq(Walk { de: self, len })
+        self.deserialize_tuple(len, visitor)
     }
 
     fn deserialize_map<V: serde::de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
-        let len: u32 = from_reader(&mut *self)?;
-        visitor.visit_map(Walk { de: self, len: len as usize })
-    }
-
-    fn deserialize_struct<V: serde::de::Visitor<'de>>(self, _name: &'static str, _fields: &'static [&'static str], visitor: V) -> Result<V::Value, Self::Error> {
-        visitor.visit_seq(Walk { de: self, len: _fields.len() })
+        self_described!(self, visitor, TAG_MAP);
+        let len = self.read_count()?;
+        self.nested(|de| visitor.visit_map(Walk::counted(de, len)))
+    }
+
+    fn deserialize_struct<V: serde::de::Visitor<'de>>(self, _name: &'static str, fields: &'static [&'static str], visitor: V) -> Result<V::Value, Self::Error> {
+        self_described!(self, visitor, TAG_MAP);
+        match self.config.struct_encoding {
+            StructEncoding::Positional => self.nested(|de| visitor.visit_seq(Walk::fields(de, fields))),
+            StructEncoding::Named => {
+                let len = self.read_len()?;
+                self.nested(|de| visitor.visit_map(NamedFields { de, len, index: 0, fields, segment: None, last: None }))
+            }
+            #[cfg(not(feature = "alloc"))]
+            StructEncoding::Presence => Err(Error::new(ErrorKind::Unsupported, "presence structs need the alloc feature").at(self.offset())),
+            #[cfg(feature = "alloc")]
+            StructEncoding::Presence => {
+                let offset = self.offset();
+                let mut bitmap = vec![0; fields.len().div_ceil(8)];
+                self.read_exact(&mut bitmap)?;
+                if !fields.len().is_multiple_of(8) && bitmap.last().is_some_and(|byte| byte >> (fields.len() % 8) != 0) {
+                    return Err(Error::new(ErrorKind::InvalidTag, "presence bitmap marks fields the struct does not have").at(offset));
+                }
+                self.nested(|de| visitor.visit_map(PresentFields { de, fields, bitmap, index: 0 }))
+            }
+        }
     }
 
-    fn deserialize_enum<V: serde::de::Visitor<'de>>(self, _name: &'static str, _variants: &'static [&'static str], visitor: V) -> Result<V::Value, Self::Error> {
-        let variant_index: u32 = from_reader(&mut *self)?;
-        visitor.visit_enum(Walk { de: self, len: 1 })
+    fn deserialize_enum<V: serde::de::Visitor<'de>>(self, name: &'static str, variants: &'static [&'static str], visitor: V) -> Result<V::Value, Self::Error> {
+        let offset = self.offset();
+        if self.config.self_describing && self.read_u8()? != TAG_VARIANT {
+            return Err(Error::new(ErrorKind::InvalidTag, format_args!("expected a variant of enum {}", name)).at(offset));
+        }
+        let variant_index = self.read_unsigned::<u32>()?;
+        if variant_index as usize >= variants.len() {
+            return Err(Error::new(ErrorKind::InvalidTag, format_args!("variant index {} is out of range for enum {} with {} variants", variant_index, name, variants.len())).at(offset));
+        }
+        if self.config.self_describing {
+            // The index alone identifies the variant, the name is only there for deserialize_any.
+            let len = self.read_len()?;
+            self.read_bytes(len)?;
+        }
+        self.nested(|de| visitor.visit_enum(Enum { de, variant_index }))
     }
 
     fn deserialize_identifier<V: serde::de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
-        Err(Error::custom("not implemented"))
+        self_described!(self, visitor, TAG_STR);
+        // Field names are the only identifiers on the wire, variants are written as indices.
+        self.visit_str_body(visitor)
     }
 
     fn deserialize_ignored_any<V: serde::de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
-        Err(Error::custom("not implemented"))
+        self_described!(self, visitor);
+        Err(Error::new(ErrorKind::Unsupported, "cannot skip values unless in self-describing mode").at(self.offset()))
     }
 }
 
-struct Walk<'de>
+struct Walk<'a, R>
 {
-    de: &'de mut Deserializer<'de>,
+    de: &'a mut Deserializer<R>,
     len: usize,
+    index: usize,
+    /// Elements run until `BREAK` instead of for `len` elements.
+    unbounded: bool,
+    /// Field names when walking a struct, used to label errors.
+    fields: Option<&'static [&'static str]>,
+    /// Bytes of the previous key of a canonical map, which the next key has to sort after.
+    #[cfg(feature = "alloc")]
+    last_key: Option<Vec<u8>>,
+}
+
+impl<'a, R> Walk<'a, R>
+{
+    fn new(de: &'a mut Deserializer<R>, len: usize) -> Self {
+        Self {
+            de,
+            len,
+            index: 0,
+            unbounded: false,
+            fields: None,
+            #[cfg(feature = "alloc")]
+            last_key: None,
+        }
+    }
+
+    /// Walk `len` elements, or up to `BREAK` when the length is not known.
+    fn counted(de: &'a mut Deserializer<R>, len: Option<usize>) -> Self {
+        Self {
+            unbounded: len.is_none(),
+            ..Self::new(de, len.unwrap_or(0))
+        }
+    }
+
+    fn fields(de: &'a mut Deserializer<R>, fields: &'static [&'static str]) -> Self {
+        Self {
+            fields: Some(fields),
+            ..Self::new(de, fields.len())
+        }
+    }
+
+    fn segment(&self, index: usize) -> PathSegment {
+        match self.fields {
+            Some(fields) => PathSegment::Field(fields[index]),
+            None => PathSegment::Index(index),
+        }
+    }
+}
+
+impl<'de, 'a, R: Read<'de>> Walk<'a, R>
+{
+    fn has_next(&mut self) -> Result<bool, Error> {
+        if !self.unbounded {
+            return Ok(self.index < self.len);
+        }
+        let offset = self.de.offset();
+        let more = if self.de.config.self_describing {
+            // Every element starts with a tag, which is never `BREAK`.
+            match self.de.reader.peek()? {
+                Some(BREAK) => {
+                    self.de.read_u8()?;
+                    false
+                }
+                Some(_) => true,
+                None => return Err(Error::new(ErrorKind::UnexpectedEof, "unexpected end of input").at(offset)),
+            }
+        } else {
+            match self.de.read_u8()? {
+                MORE => true,
+                BREAK => false,
+                _ => return Err(Error::new(ErrorKind::InvalidTag, "expected an element marker").at(offset)),
+            }
+        };
+        match self.de.config.max_len {
+            Some(limit) if more && self.index >= limit => {
+                Err(Error::new(ErrorKind::LimitExceeded, format_args!("length exceeds the limit of {} elements", limit)).at(offset))
+            }
+            _ => Ok(more),
+        }
+    }
+
+    /// Decode a key of a canonical map and check that it sorts after the key before it.
+    #[cfg(feature = "alloc")]
+    fn sorted_key<K: serde::de::DeserializeSeed<'de>>(&mut self, seed: K) -> Result<K::Value, Error> {
+        let offset = self.de.offset();
+        // Keys can hold maps of their own, whose keys are part of this key as well.
+        let outer = self.de.key.replace(Vec::new());
+        let key = seed.deserialize(&mut *self.de);
+        let bytes = core::mem::replace(&mut self.de.key, outer).unwrap_or_default();
+        if let Some(outer) = &mut self.de.key {
+            outer.extend_from_slice(&bytes);
+        }
+        let key = key?;
+        if self.last_key.as_ref().is_some_and(|last| *last >= bytes) {
+            return Err(Error::new(ErrorKind::NonCanonical, "map keys are not in ascending order or repeat").at(offset));
+        }
+        self.last_key = Some(bytes);
+        Ok(key)
+    }
+
+    #[cfg(not(feature = "alloc"))]
+    fn sorted_key<K: serde::de::DeserializeSeed<'de>>(&mut self, _seed: K) -> Result<K::Value, Error> {
+        Err(Error::new(ErrorKind::Unsupported, "canonical maps need the alloc feature").at(self.de.offset()))
+    }
 }
 
-impl<'de> serde::de::SeqAccess<'de> for Walk<'de>
+impl<'de, 'a, R: Read<'de>> serde::de::SeqAccess<'de> for Walk<'a, R>
 {
     type Error = Error;
 
     fn next_element_seed<T: serde::de::DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>, Self::Error> {
-        if self.len > 0 {
-            self.len -= 1;
-            let value = seed.deserialize(&mut *self.de)?;
+        if self.has_next()? {
+            let segment = self.segment(self.index);
+            self.index += 1;
+            let value = seed.deserialize(&mut *self.de).map_err(|err| self.de.fix_position(err).within(segment))?;
             Ok(Some(value))
         } else {
             Ok(None)
@@ -593,18 +2478,28 @@ impl<'de> serde::de::SeqAccess<'de> for Walk<'de>
     }
 
     fn size_hint(&self) -> Option<usize> {
-        Some(self.len)
+        if self.unbounded {
+            None
+        } else {
+            Some(self.len - self.index)
+        }
     }
 }
 
-impl<'de> serde::de::MapAccess<'de> for Walk<'de>
+impl<'de, 'a, R: Read<'de>> serde::de::MapAccess<'de> for Walk<'a, R>
 {
     type Error = Error;
 
     fn next_key_seed<K: serde::de::DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>, Self::Error> {
-        if self.len > 0 {
-            self.len -= 1;
-            let key = seed.deserialize(&mut *self.de)?;
+        if self.has_next()? {
+            let segment = self.segment(self.index);
+            self.index += 1;
+            let key = if self.de.config.canonical {
+                self.sorted_key(seed)
+            } else {
+                seed.deserialize(&mut *self.de)
+            };
+            let key = key.map_err(|err| self.de.fix_position(err).within(segment))?;
             Ok(Some(key))
         } else {
             Ok(None)
@@ -612,32 +2507,297 @@ impl<'de> serde::de::MapAccess<'de> for Walk<'de>
     }
 
     fn next_value_seed<V: serde::de::DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Self::Error> {
-        let value = seed.deserialize(&mut *self.de)?;
+        let segment = self.segment(self.index - 1);
+        let value = seed.deserialize(&mut *self.de).map_err(|err| self.de.fix_position(err).within(segment))?;
+        Ok(value)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        serde::de::SeqAccess::size_hint(self)
+    }
+}
+
+/// Hand a name that was already read from the input to `seed` as a string.
+fn deserialize_name<'de, K: serde::de::DeserializeSeed<'de>>(name: Bytes<'de>, seed: K) -> Result<K::Value, Error> {
+    let invalid = || Error::new(ErrorKind::InvalidUtf8, "invalid utf-8");
+    match name {
+        Bytes::Borrowed(bytes) => {
+            let name = core::str::from_utf8(bytes).map_err(|_| invalid())?;
+            seed.deserialize(serde::de::value::BorrowedStrDeserializer::new(name))
+        }
+        #[cfg(feature = "alloc")]
+        Bytes::Owned(bytes) => {
+            let name = String::from_utf8(bytes).map_err(|_| invalid())?;
+            seed.deserialize(serde::de::value::StringDeserializer::new(name))
+        }
+    }
+}
+
+/// The fields of a struct in [`StructEncoding::Named`], each a name followed by a delimited value.
+struct NamedFields<'a, R>
+{
+    de: &'a mut Deserializer<R>,
+    len: usize,
+    index: usize,
+    fields: &'static [&'static str],
+    /// Label of the field whose value comes next.
+    segment: Option<PathSegment>,
+    /// Position in `fields` of the last known field, canonical input has them in that order.
+    last: Option<usize>,
+}
+
+impl<'de, 'a, R: Read<'de>> serde::de::MapAccess<'de> for NamedFields<'a, R>
+{
+    type Error = Error;
+
+    fn next_key_seed<K: serde::de::DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>, Self::Error> {
+        if self.index == self.len {
+            return Ok(None);
+        }
+        let offset = self.de.offset();
+        let len = self.de.read_len()?;
+        let name = self.de.read_bytes(len)?;
+        let bytes = match &name {
+            Bytes::Borrowed(bytes) => *bytes,
+            #[cfg(feature = "alloc")]
+            Bytes::Owned(bytes) => bytes.as_slice(),
+        };
+        // Fields this version does not know have no static name, their position will do.
+        let position = self.fields.iter().position(|field| field.as_bytes() == bytes);
+        let segment = match position {
+            Some(position) => PathSegment::Field(self.fields[position]),
+            None => PathSegment::Index(self.index),
+        };
+        if self.de.config.canonical && position.is_some() && position <= self.last {
+            return Err(self.de.non_canonical("field order", offset).within(segment));
+        }
+        self.last = position.or(self.last);
+        self.index += 1;
+        let key = deserialize_name(name, seed).map_err(|err| err.at(offset).within(segment.clone()))?;
+        self.segment = Some(segment);
+        Ok(Some(key))
+    }
+
+    fn next_value_seed<V: serde::de::DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Self::Error> {
+        let segment = self.segment.take().unwrap_or(PathSegment::Index(self.index));
+        let len = self.de.read_len()?;
+        let start = self.de.offset();
+        let value = seed.deserialize(FieldValue { de: &mut *self.de, len }).map_err(|err| self.de.fix_position(err).within(segment.clone()))?;
+        if self.de.offset() - start != len as u64 {
+            return Err(Error::new(ErrorKind::InvalidTag, format_args!("field value does not match its length of {} bytes", len)).at(start).within(segment));
+        }
         Ok(value)
     }
 
     fn size_hint(&self) -> Option<usize> {
-        Some(self.len)
+        Some(self.len - self.index)
+    }
+}
+
+/// Forward deserializer methods to the wrapped [`Deserializer`].
+macro_rules! forward_to_inner {
+    ($($method:ident($($arg:ident: $ty:ty),*))*) => {
+        $(
+            fn $method<V: serde::de::Visitor<'de>>(self, $($arg: $ty,)* visitor: V) -> Result<V::Value, Self::Error> {
+                serde::Deserializer::$method(&mut *self.de, $($arg,)* visitor)
+            }
+        )*
+    };
+}
+
+/// The value of a named struct field, which can be skipped by its length when the struct does not
+/// know the field.
+struct FieldValue<'a, R>
+{
+    de: &'a mut Deserializer<R>,
+    len: usize,
+}
+
+impl<'de, 'a, R: Read<'de>> serde::Deserializer<'de> for FieldValue<'a, R> {
+    type Error = Error;
+
+    fn deserialize_ignored_any<V: serde::de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        self.de.read_bytes(self.len)?;
+        visitor.visit_unit()
+    }
+
+    forward_to_inner! {
+        deserialize_any()
+        deserialize_bool()
+        deserialize_i8()
+        deserialize_i16()
+        deserialize_i32()
+        deserialize_i64()
+        deserialize_i128()
+        deserialize_u8()
+        deserialize_u16()
+        deserialize_u32()
+        deserialize_u64()
+        deserialize_u128()
+        deserialize_f32()
+        deserialize_f64()
+        deserialize_char()
+        deserialize_str()
+        deserialize_string()
+        deserialize_bytes()
+        deserialize_byte_buf()
+        deserialize_option()
+        deserialize_unit()
+        deserialize_unit_struct(name: &'static str)
+        deserialize_newtype_struct(name: &'static str)
+        deserialize_seq()
+        deserialize_tuple(len: usize)
+        deserialize_tuple_struct(name: &'static str, len: usize)
+        deserialize_map()
+        deserialize_struct(name: &'static str, fields: &'static [&'static str])
+        deserialize_enum(name: &'static str, variants: &'static [&'static str])
+        deserialize_identifier()
+    }
+}
+
+/// The fields of a struct in [`StructEncoding::Presence`] whose bit is set, in declaration order.
+#[cfg(feature = "alloc")]
+struct PresentFields<'a, R>
+{
+    de: &'a mut Deserializer<R>,
+    fields: &'static [&'static str],
+    bitmap: Vec<u8>,
+    /// The next field to consider, one past the current one once its key was handed out.
+    index: usize,
+}
+
+#[cfg(feature = "alloc")]
+impl<'de, 'a, R: Read<'de>> serde::de::MapAccess<'de> for PresentFields<'a, R>
+{
+    type Error = Error;
+
+    fn next_key_seed<K: serde::de::DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>, Self::Error> {
+        while self.index < self.fields.len() {
+            let index = self.index;
+            self.index += 1;
+            if self.bitmap[index / 8] & (1 << (index % 8)) != 0 {
+                let name = serde::de::value::BorrowedStrDeserializer::<Error>::new(self.fields[index]);
+                return seed.deserialize(name).map(Some);
+            }
+        }
+        Ok(None)
+    }
+
+    fn next_value_seed<V: serde::de::DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Self::Error> {
+        let segment = PathSegment::Field(self.fields[self.index - 1]);
+        seed.deserialize(PresentValue { de: &mut *self.de }).map_err(|err| self.de.fix_position(err).within(segment))
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        let remaining = (self.index..self.fields.len()).filter(|index| self.bitmap[index / 8] & (1 << (index % 8)) != 0);
+        Some(remaining.count())
+    }
+}
+
+/// The value of a field in [`StructEncoding::Presence`], where an option at the top is known to
+/// be `Some` from the bitmap alone.
+#[cfg(feature = "alloc")]
+struct PresentValue<'a, R>
+{
+    de: &'a mut Deserializer<R>,
+}
+
+#[cfg(feature = "alloc")]
+impl<'de, 'a, R: Read<'de>> serde::Deserializer<'de> for PresentValue<'a, R> {
+    type Error = Error;
+
+    fn deserialize_option<V: serde::de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        self.de.nested(|de| visitor.visit_some(de))
+    }
+
+    forward_to_inner! {
+        deserialize_any()
+        deserialize_bool()
+        deserialize_i8()
+        deserialize_i16()
+        deserialize_i32()
+        deserialize_i64()
+        deserialize_i128()
+        deserialize_u8()
+        deserialize_u16()
+        deserialize_u32()
+        deserialize_u64()
+        deserialize_u128()
+        deserialize_f32()
+        deserialize_f64()
+        deserialize_char()
+        deserialize_str()
+        deserialize_string()
+        deserialize_bytes()
+        deserialize_byte_buf()
+        deserialize_unit()
+        deserialize_unit_struct(name: &'static str)
+        deserialize_newtype_struct(name: &'static str)
+        deserialize_seq()
+        deserialize_tuple(len: usize)
+        deserialize_tuple_struct(name: &'static str, len: usize)
+        deserialize_map()
+        deserialize_struct(name: &'static str, fields: &'static [&'static str])
+        deserialize_enum(name: &'static str, variants: &'static [&'static str])
+        deserialize_identifier()
+        deserialize_ignored_any()
+    }
+}
+
+/// A self-described variant seen through `deserialize_any`, a map with a single entry from the
+/// variant name to its payload.
+struct VariantMap<'a, 'de, R>
+{
+    de: &'a mut Deserializer<R>,
+    name: Option<Bytes<'de>>,
+}
+
+impl<'de, 'a, R: Read<'de>> serde::de::MapAccess<'de> for VariantMap<'a, 'de, R>
+{
+    type Error = Error;
+
+    fn next_key_seed<K: serde::de::DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>, Self::Error> {
+        match self.name.take() {
+            Some(name) => deserialize_name(name, seed).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V: serde::de::DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Self::Error> {
+        seed.deserialize(&mut *self.de)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.name.is_some() as usize)
     }
 }
 
-impl<'de> serde::de::EnumAccess<'de> for Walk<'de>
+/// Enum access for a variant whose index has already been read and range checked.
+struct Enum<'a, R>
+{
+    de: &'a mut Deserializer<R>,
+    variant_index: u32,
+}
+
+impl<'de, 'a, R: Read<'de>> serde::de::EnumAccess<'de> for Enum<'a, R>
 {
     type Error = Error;
     type Variant = Self;
 
     fn variant_seed<V: serde::de::DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, Self::Variant), Self::Error> {
-        let value = seed.deserialize(&mut *self.de)?;
+        // The variant identifier is the index itself, the seed never reads from the wire.
+        let deserializer: serde::de::value::U32Deserializer<Error> = self.variant_index.into_deserializer();
+        let value = seed.deserialize(deserializer)?;
         Ok((value, self))
     }
 }
 
-impl<'de> serde::de::VariantAccess<'de> for Walk<'de>
+impl<'de, 'a, R: Read<'de>> serde::de::VariantAccess<'de> for Enum<'a, R>
 {
     type Error = Error;
 
     fn unit_variant(self) -> Result<(), Self::Error> {
-        Ok(())
+        <()>::deserialize(&mut *self.de)
     }
 
     fn newtype_variant_seed<T: serde::de::DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, Self::Error> {
@@ -645,10 +2805,412 @@ impl<'de> serde::de::VariantAccess<'de> for Walk<'de>
     }
 
     fn tuple_variant<V: serde::de::Visitor<'de>>(self, len: usize, visitor: V) -> Result<V::Value, Self::Error> {
-        visitor.visit_seq(Walk { de: self.de, len })
+        serde::Deserializer::deserialize_tuple(&mut *self.de, len, visitor)
+    }
+
+    fn struct_variant<V: serde::de::Visitor<'de>>(self, fields: &'static [&'static str], visitor: V) -> Result<V::Value, Self::Error> {
+        serde::Deserializer::deserialize_struct(&mut *self.de, "", fields, visitor)
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use std::io::Write as _;
+
+    /// Yields its bytes, then reports that no more are available yet.
+    struct Stalled<'a>(&'a [u8]);
+
+    impl std::io::Read for Stalled<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.0.is_empty() {
+                return Err(std::io::ErrorKind::WouldBlock.into());
+            }
+            let n = buf.len().min(self.0.len());
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn trailing_bytes_rejected_in_slices_by_default() {
+        let mut bytes = to_bytes(&7u32).unwrap();
+        bytes.push(0);
+        assert_eq!(from_bytes::<u32>(&bytes).unwrap_err().kind(), ErrorKind::TrailingData);
+        let config = Config::default().trailing_bytes(TrailingBytes::Allow);
+        assert_eq!(config.deserialize_from_bytes::<u32>(&bytes).unwrap(), 7);
+        assert_eq!(Config::default().take_from_bytes::<u32>(&bytes).unwrap(), (7, &[0][..]));
+    }
+
+    #[test]
+    fn streams_are_not_read_past_the_value_by_default() {
+        let bytes = to_bytes(&(1u8, "two")).unwrap();
+        assert_eq!(from_reader::<(u8, String), _>(Stalled(&bytes)).unwrap(), (1, "two".to_string()));
+
+        let config = Config::default().trailing_bytes(TrailingBytes::Reject);
+        assert_eq!(config.deserialize_from_reader::<(u8, String), _>(Stalled(&bytes)).unwrap_err().kind(), ErrorKind::Io);
+        let mut with_trailer = bytes.clone();
+        with_trailer.push(0);
+        assert_eq!(config.deserialize_from_reader::<(u8, String), _>(&with_trailer[..]).unwrap_err().kind(), ErrorKind::TrailingData);
+    }
+
+    #[test]
+    fn from_reader_returns_on_an_open_socket() {
+        let (mut a, b) = std::os::unix::net::UnixStream::pair().unwrap();
+        b.set_read_timeout(Some(std::time::Duration::from_secs(5))).unwrap();
+        a.write_all(&to_bytes(&42u64).unwrap()).unwrap();
+        assert_eq!(from_reader::<u64, _>(&b).unwrap(), 42);
+        drop(a);
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    enum Message {
+        Ping,
+        Text(String),
+        Move(i32, i32),
+        Resize { width: u16, height: u16 },
+    }
+
+    fn messages() -> Vec<Message> {
+        vec![Message::Ping, Message::Text("hi".into()), Message::Move(-1, 2), Message::Resize { width: 80, height: 24 }]
+    }
+
+    #[test]
+    fn enums_round_trip() {
+        for config in [Config::default(), Config::default().int_encoding(IntEncoding::Varint), Config::default().self_describing(true)] {
+            let bytes = config.to_bytes(&messages()).unwrap();
+            assert_eq!(config.deserialize_from_bytes::<Vec<Message>>(&bytes).unwrap(), messages(), "{:?}", config);
+        }
+        let mut bytes = to_bytes(&Message::Ping).unwrap();
+        bytes[..4].copy_from_slice(&9u32.to_be_bytes());
+        assert_eq!(from_bytes::<Message>(&bytes).unwrap_err().kind(), ErrorKind::InvalidTag);
+    }
+
+    #[test]
+    fn strings_borrow_from_the_input() {
+        let bytes = to_bytes(&("borrowed", 7u8)).unwrap();
+        let (text, _): (&str, u8) = from_bytes(&bytes).unwrap();
+        assert_eq!(text, "borrowed");
+        assert!(bytes.as_ptr_range().contains(&text.as_ptr()));
+    }
+
+    #[test]
+    fn any_write_and_read_backend_works() {
+        /// A sink of its own, to check that any [`Write`] will do.
+        struct Collect(Vec<u8>);
+
+        impl Write for Collect {
+            fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), Error> {
+                self.0.extend_from_slice(bytes);
+                Ok(())
+            }
+        }
+
+        let mut serializer = Serializer::new(Collect(Vec::new()));
+        messages().serialize(&mut serializer).unwrap();
+        serializer.end().unwrap();
+        let bytes = serializer.into_inner().0;
+        assert_eq!(bytes, to_bytes(&messages()).unwrap());
+
+        let mut from_slice = Deserializer::from_slice(&bytes);
+        assert_eq!(Vec::<Message>::deserialize(&mut from_slice).unwrap(), messages());
+        let mut from_stream = Deserializer::new(std::io::Cursor::new(&bytes));
+        assert_eq!(Vec::<Message>::deserialize(&mut from_stream).unwrap(), messages());
+    }
+
+    #[test]
+    fn errors_carry_offset_and_path() {
+        #[derive(Debug, Serialize, Deserialize)]
+        struct Outer {
+            id: u8,
+            names: Vec<String>,
+        }
+
+        let mut bytes = to_bytes(&Outer { id: 1, names: vec!["ok".into(), "bad".into()] }).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] = 0xff;
+        let err = from_bytes::<Outer>(&bytes).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidUtf8);
+        assert_eq!(err.offset(), Some(last as u64 - 2));
+        assert_eq!(err.path().cloned().collect::<Vec<_>>(), [PathSegment::Field("names"), PathSegment::Index(1)]);
+        assert_eq!(from_bytes::<Outer>(&bytes[..3]).unwrap_err().kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn self_describing_input_supports_deserialize_any() {
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        #[serde(untagged)]
+        enum Loose {
+            Number(u64),
+            Pair(String, bool),
+        }
+
+        let config = Config::default().self_describing(true);
+        let values = vec![Loose::Number(3), Loose::Pair("x".into(), true)];
+        let bytes = config.to_bytes(&values).unwrap();
+        assert_eq!(config.deserialize_from_bytes::<Vec<Loose>>(&bytes).unwrap(), values);
+        assert_eq!(from_bytes::<Loose>(&to_bytes(&Loose::Number(3)).unwrap()).unwrap_err().kind(), ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn varints_are_short_for_small_numbers() {
+        let config = Config::default().int_encoding(IntEncoding::Varint);
+        assert_eq!(config.to_bytes(&1u64).unwrap(), [1]);
+        assert_eq!(config.to_bytes(&-1i64).unwrap(), [1]);
+        assert_eq!(config.to_bytes(&300u32).unwrap(), [0xac, 0x02]);
+        for value in [0, 1, -1, i64::MIN, i64::MAX] {
+            assert_eq!(config.deserialize_from_bytes::<i64>(&config.to_bytes(&value).unwrap()).unwrap(), value);
+        }
+        let err = config.deserialize_from_bytes::<u16>(&config.to_bytes(&70000u32).unwrap()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::IntegerOverflow);
+    }
+
+    #[test]
+    fn byte_order_is_configurable() {
+        assert_eq!(to_bytes(&0x0102u16).unwrap(), [1, 2]);
+        let little = Config::default().endian(Endian::Little);
+        assert_eq!(little.to_bytes(&0x0102u16).unwrap(), [2, 1]);
+        assert_eq!(little.deserialize_from_bytes::<f64>(&little.to_bytes(&1.5f64).unwrap()).unwrap(), 1.5);
+        let native = Config::default().endian(Endian::Native);
+        assert_eq!(native.to_bytes(&0x01020304u32).unwrap(), 0x01020304u32.to_ne_bytes());
+    }
+
+    #[test]
+    fn length_width_bounds_lengths() {
+        let narrow = Config::default().length_width(LengthWidth::U8);
+        assert_eq!(narrow.to_bytes(&"abc").unwrap(), [3, b'a', b'b', b'c']);
+        let long = "x".repeat(300);
+        assert_eq!(narrow.to_bytes(&long).unwrap_err().kind(), ErrorKind::LengthOverflow);
+        let wide = Config::default().length_width(LengthWidth::U64);
+        assert_eq!(wide.deserialize_from_bytes::<String>(&wide.to_bytes(&long).unwrap()).unwrap(), long);
+    }
+
+    #[test]
+    fn limits_stop_hostile_input() {
+        let bytes = to_bytes(&vec![1u8; 100]).unwrap();
+        assert_eq!(Config::default().max_len(99).deserialize_from_bytes::<Vec<u8>>(&bytes).unwrap_err().kind(), ErrorKind::LimitExceeded);
+        assert_eq!(Config::default().max_len(100).deserialize_from_bytes::<Vec<u8>>(&bytes).unwrap().len(), 100);
+        assert_eq!(Config::default().byte_limit(50).deserialize_from_bytes::<Vec<u8>>(&bytes).unwrap_err().kind(), ErrorKind::LimitExceeded);
+
+        let text = to_bytes(&"x".repeat(100)).unwrap();
+        assert_eq!(Config::default().max_alloc(10).deserialize_from_bytes::<String>(&text).unwrap_err().kind(), ErrorKind::LimitExceeded);
+
+        let nested = to_bytes(&Some(Some(Some(1u8)))).unwrap();
+        assert_eq!(Config::default().max_depth(2).deserialize_from_bytes::<Option<Option<Option<u8>>>>(&nested).unwrap_err().kind(), ErrorKind::LimitExceeded);
+
+        // A length prefix far beyond the input must fail without allocating for it.
+        let huge = u32::MAX.to_be_bytes();
+        assert_eq!(from_reader::<Vec<u64>, _>(&huge[..]).unwrap_err().kind(), ErrorKind::UnexpectedEof);
+        assert_eq!(from_bytes::<String>(&huge).unwrap_err().kind(), ErrorKind::UnexpectedEof);
+    }
+
+    /// Serializes its entries as a map in the order given, of unknown length if `sized` is false.
+    struct Entries<K, V> {
+        entries: Vec<(K, V)>,
+        sized: bool,
+    }
+
+    impl<K: Serialize, V: Serialize> Serialize for Entries<K, V> {
+        fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            use serde::ser::SerializeMap;
+            let mut map = serializer.serialize_map(self.sized.then_some(self.entries.len()))?;
+            for (key, value) in &self.entries {
+                map.serialize_entry(key, value)?;
+            }
+            map.end()
+        }
+    }
+
+    /// Serializes as a sequence of unknown length.
+    struct Unsized(Vec<u32>);
+
+    impl Serialize for Unsized {
+        fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            use serde::ser::SerializeSeq;
+            let mut seq = serializer.serialize_seq(None)?;
+            for element in &self.0 {
+                seq.serialize_element(element)?;
+            }
+            seq.end()
+        }
+    }
+
+    #[test]
+    fn sequences_and_maps_of_unknown_length() {
+        for config in [Config::default(), Config::default().self_describing(true), Config::default().canonical(true)] {
+            let bytes = config.to_bytes(&Unsized(vec![1, 2, 3])).unwrap();
+            assert_eq!(config.deserialize_from_bytes::<Vec<u32>>(&bytes).unwrap(), [1, 2, 3], "{:?}", config);
+            let map = Entries { entries: vec![(1u8, "a"), (2, "b")], sized: false };
+            let bytes = config.to_bytes(&map).unwrap();
+            let read: std::collections::BTreeMap<u8, String> = config.deserialize_from_bytes(&bytes).unwrap();
+            assert_eq!(read, [(1, "a".to_string()), (2, "b".to_string())].into());
+        }
+    }
+
+    #[test]
+    fn integers_of_128_bits() {
+        for config in [Config::default(), Config::default().int_encoding(IntEncoding::Varint), Config::default().self_describing(true)] {
+            let values = (i128::MIN, u128::MAX, -1i128, 1u128 << 100);
+            let bytes = config.to_bytes(&values).unwrap();
+            assert_eq!(config.deserialize_from_bytes::<(i128, u128, i128, u128)>(&bytes).unwrap(), values, "{:?}", config);
+        }
+        assert_eq!(to_bytes(&u128::MAX).unwrap().len(), 16);
     }
 
-    fn struct_variant<V: serde::de::Visitor<'de>>(self, _fields: &'static [&'static str], visitor: V) -> Result<V::Value, Self::Error> {
-        visitor.visit_seq(Walk { de: self.de, len: _fields.len() })
+    #[test]
+    fn named_structs_survive_added_removed_and_reordered_fields() {
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct V1 {
+            id: u32,
+            name: String,
+            obsolete: Vec<u8>,
+        }
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct V2 {
+            name: String,
+            id: u32,
+            #[serde(default)]
+            added: Option<bool>,
+        }
+
+        let config = Config::default().struct_encoding(StructEncoding::Named);
+        let old = V1 { id: 7, name: "seven".into(), obsolete: vec![1, 2] };
+        let new: V2 = config.deserialize_from_bytes(&config.to_bytes(&old).unwrap()).unwrap();
+        assert_eq!(new, V2 { name: "seven".into(), id: 7, added: None });
+
+        let err = config.deserialize_from_bytes::<V1>(&config.to_bytes(&new).unwrap()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Custom);
+    }
+
+    #[test]
+    fn presence_structs_keep_skipped_fields_apart() {
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Sparse {
+            #[serde(skip_serializing_if = "Option::is_none", default)]
+            note: Option<String>,
+            #[serde(skip_serializing_if = "Vec::is_empty", default)]
+            tags: Vec<u8>,
+            count: u8,
+        }
+
+        let config = Config::default().struct_encoding(StructEncoding::Presence);
+        for value in [
+            Sparse { note: None, tags: vec![], count: 1 },
+            Sparse { note: Some("n".into()), tags: vec![], count: 2 },
+            Sparse { note: None, tags: vec![3], count: 3 },
+        ] {
+            let bytes = config.to_bytes(&value).unwrap();
+            assert_eq!(config.deserialize_from_bytes::<Sparse>(&bytes).unwrap(), value);
+            assert_eq!(config.serialized_size(&value).unwrap(), bytes.len() as u64);
+        }
+        // Positional structs have no way to tell which field was left out.
+        let err = to_bytes(&Sparse { note: None, tags: vec![3], count: 3 }).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn stream_deserializer_ends_cleanly_between_values() {
+        let mut bytes = Vec::new();
+        let mut starts = Vec::new();
+        for message in messages() {
+            starts.push(bytes.len() as u64);
+            to_writer(&mut bytes, &message).unwrap();
+        }
+        let read: Result<Vec<Message>, Error> = Deserializer::from_slice(&bytes).into_stream().collect();
+        assert_eq!(read.unwrap(), messages());
+        let read: Result<Vec<Message>, Error> = Deserializer::new(&bytes[..]).into_stream().collect();
+        assert_eq!(read.unwrap(), messages());
+
+        let mut stream = Deserializer::from_slice(&bytes[..bytes.len() - 1]).into_stream::<Message>();
+        assert_eq!(stream.by_ref().take(3).count(), 3);
+        assert_eq!(stream.item_offset(), Some(starts[2]));
+        assert_eq!(stream.next().unwrap().unwrap_err().kind(), ErrorKind::UnexpectedEof);
+        assert!(stream.next().is_none());
+    }
+
+    #[test]
+    fn serialized_size_matches_to_bytes() {
+        let value = (messages(), "text", [1.5f32; 3], Some(u128::MAX), std::collections::BTreeMap::from([(1u8, 'x')]));
+        for config in [
+            Config::default(),
+            Config::default().int_encoding(IntEncoding::Varint),
+            Config::default().self_describing(true),
+            Config::default().struct_encoding(StructEncoding::Named),
+            Config::default().checksum(true),
+            Config::default().canonical(true),
+        ] {
+            assert_eq!(config.serialized_size(&value).unwrap(), config.to_bytes(&value).unwrap().len() as u64, "{:?}", config);
+        }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn to_slice_fills_the_buffer_or_fails() {
+        let expected = to_bytes(&messages()).unwrap();
+        let mut buf = [0; 256];
+        assert_eq!(to_slice(&messages(), &mut buf).unwrap(), &expected[..]);
+        let mut short = vec![0; expected.len() - 1];
+        assert_eq!(to_slice(&messages(), &mut short).unwrap_err().kind(), ErrorKind::BufferFull);
+
+        let mut reused = vec![0xaa];
+        to_vec_into(&messages(), &mut reused).unwrap();
+        assert_eq!(reused[1..], expected[..]);
+    }
+
+    #[test]
+    fn canonical_encoding_is_deterministic_and_strict() {
+        let config = Config::default().canonical(true);
+        let forward = Entries { entries: vec![("a", 1u8), ("b", 2)], sized: true };
+        let backward = Entries { entries: vec![("b", 2u8), ("a", 1)], sized: false };
+        assert_eq!(config.to_bytes(&forward).unwrap(), config.to_bytes(&backward).unwrap());
+        assert_eq!(config.to_bytes(&-0.0f64).unwrap(), config.to_bytes(&0.0f64).unwrap());
+        assert_eq!(config.to_bytes(&f32::NAN).unwrap(), config.to_bytes(&-f32::NAN).unwrap());
+
+        let duplicate = Entries { entries: vec![("a", 1u8), ("a", 2)], sized: true };
+        assert_eq!(config.to_bytes(&duplicate).unwrap_err().kind(), ErrorKind::NonCanonical);
+
+        let unsorted = Config::default().to_bytes(&Entries { sized: true, ..backward }).unwrap();
+        let err = config.deserialize_from_bytes::<std::collections::BTreeMap<String, u8>>(&unsorted).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NonCanonical);
+        assert!(err.to_string().starts_with("map keys are not in ascending order"), "{}", err);
+        let unknown_length = Config::default().to_bytes(&Entries { entries: vec![("a", 1u8)], sized: false }).unwrap();
+        let err = config.deserialize_from_bytes::<std::collections::BTreeMap<String, u8>>(&unknown_length).unwrap_err();
+        assert!(err.to_string().starts_with("length of unknown size"), "{}", err);
+
+        let varint = config.int_encoding(IntEncoding::Varint);
+        assert_eq!(varint.deserialize_from_bytes::<u32>(&[0x01]).unwrap(), 1);
+        for overlong in [&[0x81, 0x00][..], &[0x80, 0x80, 0x00], &[0xac, 0x82, 0x00]] {
+            let err = varint.deserialize_from_bytes::<u32>(overlong).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::NonCanonical, "{:02x?}", overlong);
+            assert_eq!(err.offset(), Some(0));
+        }
+        assert_eq!(Config::default().int_encoding(IntEncoding::Varint).deserialize_from_bytes::<u32>(&[0x81, 0x00]).unwrap(), 1);
+
+        assert_eq!(config.deserialize_from_bytes::<char>(&[1, b'x']).unwrap(), 'x');
+        let two_chars = config.deserialize_from_bytes::<char>(&[2, b'x', b'y']).unwrap_err();
+        assert_eq!((two_chars.kind(), two_chars.offset()), (ErrorKind::NonCanonical, Some(1)));
+        let overlong = config.deserialize_from_bytes::<char>(&[5, b'x', 0, 0, 0, 0]).unwrap_err();
+        assert_eq!((overlong.kind(), overlong.offset()), (ErrorKind::NonCanonical, Some(1)));
+        assert_eq!(Config::default().trailing_bytes(TrailingBytes::Allow).deserialize_from_bytes::<char>(&[2, b'x', b'y']).unwrap(), 'x');
+    }
+
+    #[test]
+    fn canonical_self_describing_input_uses_the_exact_tag() {
+        let config = Config::default().canonical(true).self_describing(true);
+        let narrow = config.to_bytes(&5u8).unwrap();
+        let err = config.deserialize_from_bytes::<u32>(&narrow).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NonCanonical);
+        assert_eq!(err.offset(), Some(0));
+        assert_eq!(config.self_describing(true).canonical(false).deserialize_from_bytes::<u32>(&narrow).unwrap(), 5);
+
+        let bytes = config.to_bytes(&"text").unwrap();
+        assert_eq!(config.deserialize_from_bytes::<Vec<u8>>(&bytes).unwrap_err().kind(), ErrorKind::NonCanonical);
+        let bytes = config.to_bytes(&(1u16, 2.0f32)).unwrap();
+        assert_eq!(config.deserialize_from_bytes::<[u16; 2]>(&bytes).unwrap_err().kind(), ErrorKind::NonCanonical);
+
+        let values = (messages(), Some('x'), (), std::collections::BTreeMap::from([(1i64, vec![true])]));
+        let bytes = config.to_bytes(&values).unwrap();
+        assert_eq!(config.deserialize_from_bytes::<(Vec<Message>, Option<char>, (), std::collections::BTreeMap<i64, Vec<bool>>)>(&bytes).unwrap(), values);
+    }
+}
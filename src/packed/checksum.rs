@@ -0,0 +1,87 @@
+//! CRC32C (Castagnoli), the checksum behind [`Config::checksum`](super::Config::checksum).
+
+/// The reversed Castagnoli polynomial.
+const POLY: u32 = 0x82f63b78;
+
+const TABLE: [u32; 256] = table();
+
+const fn table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ POLY } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// CRC32C of `bytes`.
+pub fn crc32c(bytes: &[u8]) -> u32 {
+    crc32c_append(0, bytes)
+}
+
+/// Extend `crc`, the CRC32C of some input, to cover `bytes` following that input.
+pub fn crc32c_append(crc: u32, bytes: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for byte in bytes {
+        crc = TABLE[((crc as u8) ^ byte) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use super::super::{Config, ErrorKind};
+    use super::super::framing::{FramedReader, FramedWriter};
+    use std::{string::String, vec::Vec};
+
+    #[test]
+    fn check_value() {
+        assert_eq!(crc32c(b"123456789"), 0xe3069283);
+        assert_eq!(crc32c(b""), 0);
+        assert_eq!(crc32c_append(crc32c(b"1234"), b"56789"), 0xe3069283);
+    }
+
+    #[test]
+    fn round_trip_with_checksum() {
+        let config = Config::default().checksum(true);
+        let value = (7u32, String::from("seven"), [1.5f64, -0.0]);
+        let bytes = config.to_bytes(&value).unwrap();
+        assert_eq!(bytes.len(), Config::default().to_bytes(&value).unwrap().len() + 4);
+        assert_eq!(config.deserialize_from_bytes::<(u32, String, [f64; 2])>(&bytes).unwrap(), value);
+    }
+
+    #[test]
+    fn flipped_byte_is_a_checksum_mismatch() {
+        let config = Config::default().checksum(true);
+        let mut bytes = config.to_bytes(&(7u32, String::from("seven"))).unwrap();
+        bytes[3] ^= 0x01;
+        let err = config.deserialize_from_bytes::<(u32, String)>(&bytes).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ChecksumMismatch);
+        assert_eq!(err.offset(), Some(0));
+    }
+
+    #[test]
+    fn flipped_byte_in_a_frame_fails_at_the_frame_start() {
+        let config = Config::default().checksum(true);
+        let mut writer = FramedWriter::new(Vec::new()).with_config(config);
+        writer.write(&String::from("first")).unwrap();
+        let second = writer.get_ref().len();
+        writer.write(&String::from("second")).unwrap();
+        let mut bytes = writer.into_inner();
+        bytes[second + 6] ^= 0x20;
+
+        let mut reader = FramedReader::new(&bytes[..]).with_config(config);
+        assert_eq!(reader.read::<String>().unwrap().as_deref(), Some("first"));
+        let err = reader.read::<String>().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ChecksumMismatch);
+        assert_eq!(err.offset(), Some(second as u64));
+    }
+}
@@ -0,0 +1,266 @@
+//! An optional header in front of a message that identifies the format, the settings the message
+//! was written with and the type it holds:
+//!
+//! | bytes | content                                             |
+//! |-------|-----------------------------------------------------|
+//! | 4     | [`MAGIC`]                                           |
+//! | 1     | [`FORMAT_VERSION`]                                  |
+//! | 2     | layout settings of the [`Config`], big endian       |
+//! | 8     | [`fingerprint`] of the type, big endian             |
+//!
+//! The value follows, then the checksum trailer if [`Config::checksum`] is on, which covers the
+//! header as well.
+
+use serde::Serialize;
+use serde::Deserialize;
+
+use super::schema;
+use super::{Config, Deserializer, Endian, Error, ErrorKind, IntEncoding, LengthWidth, Read, Serializer, StructEncoding, Vec, Write};
+
+/// First bytes of every enveloped message.
+pub const MAGIC: [u8; 4] = *b"PAKD";
+
+/// Revision of the encoding and of the fingerprint, bumped whenever either changes.
+pub const FORMAT_VERSION: u8 = 1;
+
+const HEADER_LEN: usize = 15;
+
+/// A stable 64-bit hash of the serde shape of `T`, the [`schema::Schema`] traced from
+/// `T::deserialize`: the names of its structs, fields, enums and variants, the payload of every
+/// variant and the primitive types underneath.
+///
+/// Fails with the error of [`schema::trace`] for types it cannot trace, such as untagged enums or a
+/// generic type used with two different parameters. Such types cannot be enveloped, since the
+/// header could not tell them apart.
+pub fn fingerprint<'de, T: Deserialize<'de>>() -> Result<u64, Error> {
+    let mut serializer = Serializer::new(Fnv(FNV_OFFSET));
+    schema::trace::<T>()?.serialize(&mut serializer)?;
+    Ok(serializer.into_inner().0)
+}
+
+pub fn to_bytes_enveloped<'de, T: Serialize + Deserialize<'de>>(value: &T) -> Result<Vec<u8>, Error> {
+    Config::default().to_bytes_enveloped(value)
+}
+
+pub fn from_bytes_enveloped<'de, T: Deserialize<'de>>(bytes: &'de [u8]) -> Result<T, Error> {
+    Config::default().deserialize_from_bytes_enveloped(bytes)
+}
+
+impl Config
+{
+    /// Like [`Config::to_bytes`], behind a header that the `*_enveloped` decoders check.
+    /// `T` has to be [`Deserialize`] as well because its [`fingerprint`] is taken from there.
+    pub fn to_bytes_enveloped<'de, T: Serialize + Deserialize<'de>>(self, value: &T) -> Result<Vec<u8>, Error> {
+        let mut bytes = Vec::new();
+        self.to_writer_enveloped(&mut bytes, value)?;
+        Ok(bytes)
+    }
+
+    pub fn to_writer_enveloped<'de, W: Write, T: Serialize + Deserialize<'de>>(self, writer: W, value: &T) -> Result<(), Error> {
+        let mut serializer = Serializer::new(writer).with_config(self);
+        serializer.write(&self.header::<T>()?)?;
+        value.serialize(&mut serializer)?;
+        serializer.end()
+    }
+
+    /// Like [`Config::deserialize_from_bytes`], after checking the header written by
+    /// [`Config::to_bytes_enveloped`].
+    pub fn deserialize_from_bytes_enveloped<'de, T: Deserialize<'de>>(self, bytes: &'de [u8]) -> Result<T, Error> {
+        let mut deserializer = Deserializer::from_slice(bytes).with_config(self);
+        deserializer.verify_checksum_upfront()?;
+        deserializer.read_header::<T>()?;
+        deserializer.deserialize_value()
+    }
+
+    #[cfg(feature = "std")]
+    pub fn deserialize_from_reader_enveloped<T, R>(self, reader: R) -> Result<T, Error>
+    where
+        T: serde::de::DeserializeOwned,
+        R: std::io::Read,
+    {
+        let mut deserializer = Deserializer::new(reader).with_config(self);
+        deserializer.read_header::<T>()?;
+        deserializer.deserialize_value()
+    }
+
+    /// The settings that change how values are laid out, packed into bits. Limits only affect
+    /// what a reader accepts and are left out.
+    fn layout_flags(&self) -> u16 {
+        let little_endian = match self.endian {
+            Endian::Big => false,
+            Endian::Little => true,
+            Endian::Native => cfg!(target_endian = "little"),
+        };
+        let length_width = match self.length_width {
+            LengthWidth::U8 => 0,
+            LengthWidth::U16 => 1,
+            LengthWidth::U32 => 2,
+            LengthWidth::U64 => 3,
+        };
+        let struct_encoding = match self.struct_encoding {
+            StructEncoding::Positional => 0,
+            StructEncoding::Named => 1,
+            StructEncoding::Presence => 2,
+        };
+        self.self_describing as u16
+            | ((self.int_encoding == IntEncoding::Varint) as u16) << 1
+            | (little_endian as u16) << 2
+            | length_width << 3
+            | struct_encoding << 5
+            | (self.checksum as u16) << 7
+            | (self.canonical as u16) << 8
+    }
+
+    fn header<'de, T: Deserialize<'de>>(&self) -> Result<[u8; HEADER_LEN], Error> {
+        let mut header = [0; HEADER_LEN];
+        header[..4].copy_from_slice(&MAGIC);
+        header[4] = FORMAT_VERSION;
+        header[5..7].copy_from_slice(&self.layout_flags().to_be_bytes());
+        header[7..].copy_from_slice(&fingerprint::<T>()?.to_be_bytes());
+        Ok(header)
+    }
+}
+
+impl<'de, R: Read<'de>> Deserializer<R>
+{
+    fn read_header<T: Deserialize<'de>>(&mut self) -> Result<(), Error> {
+        let expected = self.config.header::<T>()?;
+        let mut header = [0; HEADER_LEN];
+        self.read_exact(&mut header)?;
+        if header[..4] != MAGIC {
+            return Err(Error::new(ErrorKind::BadMagic, "input is not an enveloped message").at(0));
+        }
+        if header[4] != expected[4] {
+            return Err(Error::new(ErrorKind::VersionMismatch, format_args!("message has format version {}, expected {}", header[4], expected[4])).at(4));
+        }
+        if header[5..7] != expected[5..7] {
+            let flags = |header: &[u8]| u16::from_be_bytes([header[5], header[6]]);
+            return Err(Error::new(ErrorKind::ConfigMismatch, format_args!("message was written with settings {:#06x}, expected {:#06x}", flags(&header), flags(&expected))).at(5));
+        }
+        if header[7..] != expected[7..] {
+            return Err(Error::new(ErrorKind::TypeMismatch, format_args!("message holds a different type than {}", core::any::type_name::<T>())).at(7));
+        }
+        Ok(())
+    }
+}
+
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+/// Hashes what is written to it, FNV-1a.
+struct Fnv(u64);
+
+impl Write for Fnv
+{
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(FNV_PRIME);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use core::num::NonZeroU8;
+    use std::boxed::Box;
+    use std::string::String;
+
+    mod a {
+        #[derive(serde::Serialize, serde::Deserialize)]
+        pub enum Msg { Ping, Data(u32) }
+    }
+
+    mod b {
+        use std::string::String;
+
+        #[derive(serde::Serialize, serde::Deserialize)]
+        pub enum Msg { Ping, Data(String) }
+    }
+
+    #[derive(Deserialize)]
+    #[allow(dead_code)]
+    struct Tree { children: Vec<Tree>, label: u8 }
+
+    #[derive(Deserialize)]
+    #[allow(dead_code)]
+    struct OtherTree { children: Vec<OtherTree>, label: String }
+
+    #[derive(Deserialize)]
+    #[allow(dead_code)]
+    struct List { next: Option<Box<List>>, value: u8 }
+
+    #[derive(Deserialize)]
+    #[allow(dead_code)]
+    struct OtherList { next: Option<Box<OtherList>>, value: String }
+
+    #[derive(Deserialize)]
+    #[allow(dead_code)]
+    struct Counted { count: NonZeroU8, rest: u8 }
+
+    #[derive(Deserialize)]
+    #[allow(dead_code)]
+    struct OtherCounted { count: NonZeroU8, rest: String }
+
+    #[test]
+    fn fingerprint_covers_every_variant() {
+        assert_ne!(fingerprint::<a::Msg>().unwrap(), fingerprint::<b::Msg>().unwrap());
+    }
+
+    #[test]
+    fn fingerprint_covers_fields_after_recursion() {
+        assert_ne!(fingerprint::<Tree>().unwrap(), fingerprint::<OtherTree>().unwrap());
+        assert_ne!(fingerprint::<List>().unwrap(), fingerprint::<OtherList>().unwrap());
+    }
+
+    #[test]
+    fn fingerprint_covers_fields_after_nonzero() {
+        assert_ne!(fingerprint::<Counted>().unwrap(), fingerprint::<OtherCounted>().unwrap());
+    }
+
+    #[test]
+    fn wrong_type_is_a_type_mismatch() {
+        let bytes = to_bytes_enveloped(&a::Msg::Data(7)).unwrap();
+        assert!(matches!(from_bytes_enveloped::<a::Msg>(&bytes).unwrap(), a::Msg::Data(7)));
+        let err = from_bytes_enveloped::<b::Msg>(&bytes).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::TypeMismatch);
+        assert_eq!(err.offset(), Some(7));
+    }
+
+    #[test]
+    fn canonical_is_part_of_the_header() {
+        let bytes = Config::default().canonical(true).to_bytes_enveloped(&7u32).unwrap();
+        let err = from_bytes_enveloped::<u32>(&bytes).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ConfigMismatch);
+        assert_eq!(err.offset(), Some(5));
+        assert_eq!(Config::default().canonical(true).deserialize_from_bytes_enveloped::<u32>(&bytes).unwrap(), 7);
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct Wrapper<T>(T);
+
+    #[derive(Serialize, Deserialize)]
+    struct Mixed { a: Wrapper<u8>, b: Wrapper<u64> }
+
+    #[derive(Serialize, Deserialize)]
+    struct OtherMixed { x: Wrapper<String>, y: Wrapper<bool>, z: Vec<u8> }
+
+    #[derive(Serialize, Deserialize)]
+    #[serde(untagged)]
+    enum Loose { Number(u64), Text(String) }
+
+    #[test]
+    fn untraceable_types_cannot_be_enveloped() {
+        assert_eq!(fingerprint::<Mixed>().unwrap_err().kind(), ErrorKind::Unsupported);
+        assert!(fingerprint::<OtherMixed>().is_err());
+        assert!(fingerprint::<Loose>().is_err());
+        let mixed = Mixed { a: Wrapper(1), b: Wrapper(2) };
+        assert_eq!(to_bytes_enveloped(&mixed).unwrap_err().kind(), ErrorKind::Unsupported);
+        assert!(to_bytes_enveloped(&Loose::Number(1)).is_err());
+
+        let bytes = Config::default().to_bytes(&(*b"PAKD", FORMAT_VERSION, 0u16, 0u64, 1u8, 2u64)).unwrap();
+        assert_eq!(from_bytes_enveloped::<Mixed>(&bytes).err().unwrap().kind(), ErrorKind::Unsupported);
+    }
+}
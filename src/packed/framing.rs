@@ -0,0 +1,371 @@
+//! Length-delimited frames for sending packed values over sockets and pipes.
+//!
+//! Every frame is the length of the payload as a big-endian `u32` followed by one value encoded
+//! with the configured [`Config`]. Both ends resume where they stopped when the underlying stream
+//! returns `WouldBlock` or `Interrupted`, so they also work on non-blocking streams.
+//!
+//! With [`Config::checksum`] every payload ends in a CRC32C trailer, and a damaged frame fails with
+//! [`ErrorKind::ChecksumMismatch`] at the offset where the frame starts.
+
+use serde::Serialize;
+
+use super::{Config, Error, ErrorKind, Serializer, Vec};
+
+/// Largest frame accepted unless configured otherwise, 16 MiB.
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 16 << 20;
+
+const PREFIX_LEN: usize = 4;
+
+/// Writes one frame per value.
+pub struct FramedWriter<W>
+{
+    writer: W,
+    config: Config,
+    max_frame_size: usize,
+    /// Encoded frames not yet accepted by the writer.
+    pending: Vec<u8>,
+    /// How much of `pending` was written already.
+    written: usize,
+}
+
+impl<W: std::io::Write> FramedWriter<W>
+{
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            config: Config::default(),
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            pending: Vec::new(),
+            written: 0,
+        }
+    }
+
+    pub fn with_config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Refuse to write values that encode to more than `size` bytes.
+    pub fn with_max_frame_size(mut self, size: usize) -> Self {
+        self.max_frame_size = size;
+        self
+    }
+
+    /// Encode `value` as one frame and send it along with any frames still queued.
+    ///
+    /// If the writer fails the frame stays queued, and [`FramedWriter::flush`] or the next write
+    /// continues from where it stopped.
+    pub fn write<T: Serialize>(&mut self, value: &T) -> Result<(), Error> {
+        let start = self.pending.len();
+        self.pending.extend_from_slice(&[0; PREFIX_LEN]);
+        let mut serializer = Serializer::new(&mut self.pending).with_config(self.config);
+        if let Err(err) = value.serialize(&mut serializer).and_then(|()| serializer.end()) {
+            self.pending.truncate(start);
+            return Err(err);
+        }
+        let len = self.pending.len() - start - PREFIX_LEN;
+        if len > self.max_frame_size || len > u32::MAX as usize {
+            self.pending.truncate(start);
+            return Err(Error::new(ErrorKind::LimitExceeded, format_args!("frame of {} bytes exceeds the maximum frame size of {} bytes", len, self.max_frame_size)));
+        }
+        self.pending[start..start + PREFIX_LEN].copy_from_slice(&(len as u32).to_be_bytes());
+        self.flush()
+    }
+
+    /// Send all queued frames and flush the writer.
+    pub fn flush(&mut self) -> Result<(), Error> {
+        while self.written < self.pending.len() {
+            match self.writer.write(&self.pending[self.written..]) {
+                Ok(0) => return Err(Error::io(std::io::ErrorKind::WriteZero.into())),
+                Ok(n) => self.written += n,
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(Error::io(err)),
+            }
+        }
+        self.pending.clear();
+        self.written = 0;
+        self.writer.flush().map_err(Error::io)
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.writer
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.writer
+    }
+
+    /// The underlying writer. Frames that were not sent yet are dropped.
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+/// Reads the frames written by a [`FramedWriter`] and decodes one value from each.
+pub struct FramedReader<R>
+{
+    reader: R,
+    config: Config,
+    max_frame_size: usize,
+    /// Bytes consumed from the reader.
+    offset: u64,
+    /// Offset of the frame being read.
+    frame_offset: u64,
+    prefix: [u8; PREFIX_LEN],
+    /// Payload length, once the prefix is complete.
+    frame_len: Option<usize>,
+    /// Bytes of the prefix or the payload read so far.
+    filled: usize,
+    frame: Vec<u8>,
+}
+
+impl<R: std::io::Read> FramedReader<R>
+{
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            config: Config::default(),
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            offset: 0,
+            frame_offset: 0,
+            prefix: [0; PREFIX_LEN],
+            frame_len: None,
+            filled: 0,
+            frame: Vec::new(),
+        }
+    }
+
+    pub fn with_config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Refuse frames longer than `size` bytes before allocating room for them.
+    pub fn with_max_frame_size(mut self, size: usize) -> Self {
+        self.max_frame_size = size;
+        self
+    }
+
+    /// Read the next frame and decode it, `None` if the stream ended cleanly between two frames.
+    ///
+    /// A frame must hold exactly one value. Error offsets count from the start of the stream. If
+    /// the reader fails, the part of the frame read so far is kept and the next call continues from
+    /// there.
+    pub fn read<T: serde::de::DeserializeOwned>(&mut self) -> Result<Option<T>, Error> {
+        let len = match self.frame_len {
+            Some(len) => len,
+            None => {
+                if self.filled == 0 {
+                    self.frame_offset = self.offset;
+                }
+                while self.filled < PREFIX_LEN {
+                    match self.reader.read(&mut self.prefix[self.filled..]) {
+                        Ok(0) if self.filled == 0 => return Ok(None),
+                        Ok(0) => return Err(Error::new(ErrorKind::UnexpectedEof, "stream ended inside a frame length").at(self.offset)),
+                        Ok(n) => {
+                            self.filled += n;
+                            self.offset += n as u64;
+                        }
+                        Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+                        Err(err) => return Err(Error::io(err)),
+                    }
+                }
+                let len = u32::from_be_bytes(self.prefix) as usize;
+                if len > self.max_frame_size {
+                    return Err(Error::new(ErrorKind::LimitExceeded, format_args!("frame of {} bytes exceeds the maximum frame size of {} bytes", len, self.max_frame_size)).at(self.frame_offset));
+                }
+                self.frame.resize(len, 0);
+                self.frame_len = Some(len);
+                self.filled = 0;
+                len
+            }
+        };
+        while self.filled < len {
+            match self.reader.read(&mut self.frame[self.filled..len]) {
+                Ok(0) => return Err(Error::new(ErrorKind::UnexpectedEof, format_args!("stream ended {} bytes into a frame of {} bytes", self.filled, len)).at(self.offset)),
+                Ok(n) => {
+                    self.filled += n;
+                    self.offset += n as u64;
+                }
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(Error::io(err)),
+            }
+        }
+        self.frame_len = None;
+        self.filled = 0;
+        self.config.deserialize_from_bytes(&self.frame[..len]).map(Some).map_err(|mut err| {
+            let payload_offset = self.frame_offset + PREFIX_LEN as u64;
+            err.offset = match err.kind() {
+                ErrorKind::ChecksumMismatch => Some(self.frame_offset),
+                _ => err.offset.map(|offset| payload_offset + offset),
+            };
+            err
+        })
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.reader
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.reader
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+    use std::io;
+    use std::{string::String, vec};
+
+    /// Hands out its steps one read at a time: some bytes, or an error.
+    struct Script(VecDeque<Result<Vec<u8>, io::ErrorKind>>);
+
+    impl io::Read for Script {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.0.pop_front() {
+                None => Ok(0),
+                Some(Err(kind)) => Err(kind.into()),
+                Some(Ok(mut bytes)) => {
+                    let n = bytes.len().min(buf.len());
+                    buf[..n].copy_from_slice(&bytes[..n]);
+                    if n < bytes.len() {
+                        self.0.push_front(Ok(bytes.split_off(n)));
+                    }
+                    Ok(n)
+                }
+            }
+        }
+    }
+
+    /// Takes `budget` bytes, then blocks until the budget is raised.
+    struct Choked {
+        written: Vec<u8>,
+        budget: usize,
+    }
+
+    impl io::Write for Choked {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.budget == 0 {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            let n = buf.len().min(self.budget);
+            self.written.extend_from_slice(&buf[..n]);
+            self.budget -= n;
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn frames() -> Vec<u8> {
+        let mut writer = FramedWriter::new(Vec::new());
+        writer.write(&(1u8, String::from("one"))).unwrap();
+        writer.write(&(2u8, String::from("two"))).unwrap();
+        writer.into_inner()
+    }
+
+    #[test]
+    fn round_trip_over_a_socket() {
+        let (left, right) = std::os::unix::net::UnixStream::pair().unwrap();
+        let sender = std::thread::spawn(move || {
+            let mut writer = FramedWriter::new(left);
+            for i in 0..100u32 {
+                writer.write(&(i, vec![i; i as usize])).unwrap();
+            }
+        });
+        let mut reader = FramedReader::new(right);
+        for i in 0..100u32 {
+            assert_eq!(reader.read::<(u32, Vec<u32>)>().unwrap(), Some((i, vec![i; i as usize])));
+        }
+        sender.join().unwrap();
+        assert_eq!(reader.read::<(u32, Vec<u32>)>().unwrap(), None);
+    }
+
+    #[test]
+    fn reads_resume_inside_the_prefix_and_the_payload() {
+        let bytes = frames();
+        let mut steps = VecDeque::new();
+        for (i, byte) in bytes.iter().enumerate() {
+            if i % 2 == 0 {
+                steps.push_back(Err(io::ErrorKind::WouldBlock));
+            } else if i % 3 == 0 {
+                steps.push_back(Err(io::ErrorKind::Interrupted));
+            }
+            steps.push_back(Ok(vec![*byte]));
+        }
+        let mut reader = FramedReader::new(Script(steps));
+        let mut values = Vec::new();
+        let mut blocked = 0;
+        loop {
+            match reader.read::<(u8, String)>() {
+                Ok(Some(value)) => values.push(value),
+                Ok(None) => break,
+                Err(err) => {
+                    assert_eq!(err.kind(), ErrorKind::Io);
+                    blocked += 1;
+                }
+            }
+        }
+        assert_eq!(values, [(1, String::from("one")), (2, String::from("two"))]);
+        assert_eq!(blocked, bytes.len().div_ceil(2));
+    }
+
+    #[test]
+    fn writes_resume_after_would_block() {
+        let mut writer = FramedWriter::new(Choked { written: Vec::new(), budget: 2 });
+        assert_eq!(writer.write(&(1u8, String::from("one"))).unwrap_err().kind(), ErrorKind::Io);
+        writer.get_mut().budget = 5;
+        assert_eq!(writer.write(&(2u8, String::from("two"))).unwrap_err().kind(), ErrorKind::Io);
+        writer.get_mut().budget = usize::MAX;
+        writer.flush().unwrap();
+        assert_eq!(writer.into_inner().written, frames());
+    }
+
+    #[test]
+    fn eof_between_frames_is_clean() {
+        let bytes = frames();
+        let mut reader = FramedReader::new(&bytes[..]);
+        assert!(reader.read::<(u8, String)>().unwrap().is_some());
+        assert!(reader.read::<(u8, String)>().unwrap().is_some());
+        assert_eq!(reader.read::<(u8, String)>().unwrap(), None);
+    }
+
+    #[test]
+    fn eof_inside_a_frame_is_an_error() {
+        let bytes = frames();
+        for cut in [bytes.len() - 1, bytes.len() / 2 + 2, 2] {
+            let mut reader = FramedReader::new(&bytes[..cut]);
+            let err = loop {
+                match reader.read::<(u8, String)>() {
+                    Ok(value) => assert!(value.is_some(), "stream cut at {} ended cleanly", cut),
+                    Err(err) => break err,
+                }
+            };
+            assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+            assert_eq!(err.offset(), Some(cut as u64));
+        }
+    }
+
+    #[test]
+    fn oversized_prefix_is_rejected_before_allocating() {
+        let mut bytes = u32::MAX.to_be_bytes().to_vec();
+        bytes.extend_from_slice(&[0; 16]);
+        let mut reader = FramedReader::new(&bytes[..]);
+        let err = reader.read::<Vec<u8>>().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::LimitExceeded);
+        assert_eq!(err.offset(), Some(0));
+        assert_eq!(reader.frame.capacity(), 0);
+
+        let mut reader = FramedReader::new(&[0, 0, 0, 9][..]).with_max_frame_size(8);
+        assert_eq!(reader.read::<Vec<u8>>().unwrap_err().kind(), ErrorKind::LimitExceeded);
+        assert_eq!(reader.frame.capacity(), 0);
+    }
+}
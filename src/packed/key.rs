@@ -0,0 +1,898 @@
+//! An order-preserving encoding for keys of sorted stores: whenever `a < b` under `Ord`, the
+//! encoding of `a` sorts before the encoding of `b` when compared byte by byte.
+//!
+//! | value                         | encoding                                                   |
+//! |-------------------------------|------------------------------------------------------------|
+//! | `bool`                        | `0x00` or `0x01`                                           |
+//! | unsigned integers, `char`     | big endian                                                 |
+//! | signed integers               | big endian with the sign bit flipped                       |
+//! | floats                        | big endian, sign bit flipped for positive numbers and all  |
+//! |                               | bits flipped for negative ones, ordered like `total_cmp`   |
+//! | strings and bytes             | `0x00` escaped as `0x00 0xff`, ended by `0x00 0x00`        |
+//! | options                       | `0x00` for `None`, `0x01` and the value for `Some`         |
+//! | sequences and maps            | `0x01` before every element or entry, `0x00` at the end    |
+//! | tuples and structs            | the fields back to back                                    |
+//! | enums                         | the variant index as a big-endian `u32`, then the payload  |
+//!
+//! The encoding has no settings and is not self-describing; a [`Deserializer`] only takes the
+//! depth limit from a [`Config`]. Maps are written in iteration order, so only maps that iterate
+//! in order, like `BTreeMap`, keep their ordering.
+
+use serde::Serialize;
+use serde::Deserialize;
+use serde::de::IntoDeserializer;
+#[cfg(feature = "alloc")]
+use super::{String, Vec};
+
+use super::{Config, Error, ErrorKind, Write};
+
+/// Ends a string or byte array. A zero byte inside one is followed by `ESCAPED` instead.
+const END: [u8; 2] = [0x00, 0x00];
+const ESCAPED: u8 = 0xff;
+
+/// Precedes every element of a sequence or map.
+const ELEMENT: u8 = 0x01;
+/// Ends a sequence or map.
+const LAST: u8 = 0x00;
+
+#[cfg(feature = "alloc")]
+pub fn to_bytes<T: ?Sized + Serialize>(value: &T) -> Result<Vec<u8>, Error> {
+    let mut serializer = Serializer::new(Vec::new());
+    value.serialize(&mut serializer)?;
+    Ok(serializer.into_inner())
+}
+
+pub fn to_writer<W: Write, T: ?Sized + Serialize>(writer: W, value: &T) -> Result<(), Error> {
+    value.serialize(&mut Serializer::new(writer))
+}
+
+/// Decode a key, which has to span all of `bytes`.
+pub fn from_bytes<'de, T: Deserialize<'de>>(bytes: &'de [u8]) -> Result<T, Error> {
+    let mut deserializer = Deserializer::from_slice(bytes);
+    let value = T::deserialize(&mut deserializer).map_err(|err| err.at(deserializer.offset as u64))?;
+    if !deserializer.input.is_empty() {
+        return Err(Error::new(ErrorKind::TrailingData, "trailing bytes after the key").at(deserializer.offset as u64));
+    }
+    Ok(value)
+}
+
+pub struct Serializer<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> Serializer<W>
+{
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        self.writer.write_bytes(bytes)
+    }
+
+    /// Write the bytes of a string or byte array with every zero byte escaped, without the end
+    /// marker.
+    fn write_escaped(&mut self, mut bytes: &[u8]) -> Result<(), Error> {
+        while let Some(zero) = bytes.iter().position(|&byte| byte == 0) {
+            self.write(&bytes[..zero])?;
+            self.write(&[0x00, ESCAPED])?;
+            bytes = &bytes[zero + 1..];
+        }
+        self.write(bytes)
+    }
+}
+
+impl<W: Write> serde::Serializer for &mut Serializer<W>
+{
+    type Ok = ();
+    type Error = Error;
+
+    type SerializeSeq = Self;
+    type SerializeTuple = Self;
+    type SerializeTupleStruct = Self;
+    type SerializeTupleVariant = Self;
+    type SerializeMap = Self;
+    type SerializeStruct = Self;
+    type SerializeStructVariant = Self;
+
+    fn serialize_bool(self, v: bool) -> Result<Self::Ok, Self::Error> {
+        self.write(&[v as u8])
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<Self::Ok, Self::Error> {
+        self.serialize_u8(v as u8 ^ (1 << 7))
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<Self::Ok, Self::Error> {
+        self.serialize_u16(v as u16 ^ (1 << 15))
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<Self::Ok, Self::Error> {
+        self.serialize_u32(v as u32 ^ (1 << 31))
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<Self::Ok, Self::Error> {
+        self.serialize_u64(v as u64 ^ (1 << 63))
+    }
+
+    fn serialize_i128(self, v: i128) -> Result<Self::Ok, Self::Error> {
+        self.serialize_u128(v as u128 ^ (1 << 127))
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<Self::Ok, Self::Error> {
+        self.write(&[v])
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<Self::Ok, Self::Error> {
+        self.write(&v.to_be_bytes())
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<Self::Ok, Self::Error> {
+        self.write(&v.to_be_bytes())
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<Self::Ok, Self::Error> {
+        self.write(&v.to_be_bytes())
+    }
+
+    fn serialize_u128(self, v: u128) -> Result<Self::Ok, Self::Error> {
+        self.write(&v.to_be_bytes())
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<Self::Ok, Self::Error> {
+        let bits = v.to_bits();
+        self.serialize_u32(if bits >> 31 == 0 { bits ^ (1 << 31) } else { !bits })
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<Self::Ok, Self::Error> {
+        let bits = v.to_bits();
+        self.serialize_u64(if bits >> 63 == 0 { bits ^ (1 << 63) } else { !bits })
+    }
+
+    fn serialize_char(self, v: char) -> Result<Self::Ok, Self::Error> {
+        self.serialize_u32(v as u32)
+    }
+
+    fn serialize_str(self, v: &str) -> Result<Self::Ok, Self::Error> {
+        self.serialize_bytes(v.as_bytes())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<Self::Ok, Self::Error> {
+        self.write_escaped(v)?;
+        self.write(&END)
+    }
+
+    fn serialize_none(self) -> Result<Self::Ok, Self::Error> {
+        self.write(&[0x00])
+    }
+
+    fn serialize_some<T: ?Sized + Serialize>(self, value: &T) -> Result<Self::Ok, Self::Error> {
+        self.write(&[0x01])?;
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Self::Ok, Self::Error> {
+        Ok(())
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Self::Ok, Self::Error> {
+        Ok(())
+    }
+
+    fn serialize_unit_variant(self, _name: &'static str, variant_index: u32, _variant: &'static str) -> Result<Self::Ok, Self::Error> {
+        self.serialize_u32(variant_index)
+    }
+
+    fn serialize_newtype_struct<T: ?Sized + Serialize>(self, _name: &'static str, value: &T) -> Result<Self::Ok, Self::Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: ?Sized + Serialize>(self, _name: &'static str, variant_index: u32, _variant: &'static str, value: &T) -> Result<Self::Ok, Self::Error> {
+        self.serialize_u32(variant_index)?;
+        value.serialize(self)
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq, Self::Error> {
+        Ok(self)
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Self::SerializeTuple, Self::Error> {
+        Ok(self)
+    }
+
+    fn serialize_tuple_struct(self, _name: &'static str, _len: usize) -> Result<Self::SerializeTupleStruct, Self::Error> {
+        Ok(self)
+    }
+
+    fn serialize_tuple_variant(self, _name: &'static str, variant_index: u32, _variant: &'static str, _len: usize) -> Result<Self::SerializeTupleVariant, Self::Error> {
+        self.serialize_u32(variant_index)?;
+        Ok(self)
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap, Self::Error> {
+        Ok(self)
+    }
+
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<Self::SerializeStruct, Self::Error> {
+        Ok(self)
+    }
+
+    fn serialize_struct_variant(self, _name: &'static str, variant_index: u32, _variant: &'static str, _len: usize) -> Result<Self::SerializeStructVariant, Self::Error> {
+        self.serialize_u32(variant_index)?;
+        Ok(self)
+    }
+
+    /// Escapes the text as it is formatted rather than collecting it first.
+    fn collect_str<T: ?Sized + core::fmt::Display>(self, value: &T) -> Result<Self::Ok, Self::Error> {
+        use core::fmt::Write as _;
+        let mut writer = Escaper { ser: self, error: None };
+        if write!(writer, "{}", value).is_err() {
+            return Err(writer.error.unwrap_or_else(|| Error::new(ErrorKind::Custom, "formatting failed")));
+        }
+        writer.ser.write(&END)
+    }
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+}
+
+/// Passes formatted text on to [`Serializer::write_escaped`].
+struct Escaper<'a, W: Write> {
+    ser: &'a mut Serializer<W>,
+    error: Option<Error>,
+}
+
+impl<W: Write> core::fmt::Write for Escaper<'_, W>
+{
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        self.ser.write_escaped(s.as_bytes()).map_err(|err| {
+            self.error = Some(err);
+            core::fmt::Error
+        })
+    }
+}
+
+impl<W: Write> serde::ser::SerializeSeq for &mut Serializer<W>
+{
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Self::Error> {
+        self.write(&[ELEMENT])?;
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<Self::Ok, Self::Error> {
+        self.write(&[LAST])
+    }
+}
+
+impl<W: Write> serde::ser::SerializeTuple for &mut Serializer<W>
+{
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Self::Error> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<Self::Ok, Self::Error> {
+        Ok(())
+    }
+}
+
+impl<W: Write> serde::ser::SerializeTupleStruct for &mut Serializer<W>
+{
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Self::Error> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<Self::Ok, Self::Error> {
+        Ok(())
+    }
+}
+
+impl<W: Write> serde::ser::SerializeTupleVariant for &mut Serializer<W>
+{
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Self::Error> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<Self::Ok, Self::Error> {
+        Ok(())
+    }
+}
+
+impl<W: Write> serde::ser::SerializeMap for &mut Serializer<W>
+{
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_key<T: ?Sized + Serialize>(&mut self, key: &T) -> Result<(), Self::Error> {
+        self.write(&[ELEMENT])?;
+        key.serialize(&mut **self)
+    }
+
+    fn serialize_value<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Self::Error> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<Self::Ok, Self::Error> {
+        self.write(&[LAST])
+    }
+}
+
+impl<W: Write> serde::ser::SerializeStruct for &mut Serializer<W>
+{
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, _key: &'static str, value: &T) -> Result<(), Self::Error> {
+        value.serialize(&mut **self)
+    }
+
+    fn skip_field(&mut self, key: &'static str) -> Result<(), Self::Error> {
+        Err(Error::new(ErrorKind::Unsupported, format_args!("field {} is skipped, which keys cannot represent", key)))
+    }
+
+    fn end(self) -> Result<Self::Ok, Self::Error> {
+        Ok(())
+    }
+}
+
+impl<W: Write> serde::ser::SerializeStructVariant for &mut Serializer<W>
+{
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, key: &'static str, value: &T) -> Result<(), Self::Error> {
+        serde::ser::SerializeStruct::serialize_field(self, key, value)
+    }
+
+    fn skip_field(&mut self, key: &'static str) -> Result<(), Self::Error> {
+        serde::ser::SerializeStruct::skip_field(self, key)
+    }
+
+    fn end(self) -> Result<Self::Ok, Self::Error> {
+        Ok(())
+    }
+}
+
+/// Decodes keys out of a buffer, borrowing strings and byte arrays that contain no zero bytes.
+pub struct Deserializer<'de>
+{
+    input: &'de [u8],
+    offset: usize,
+    depth: usize,
+    max_depth: usize,
+}
+
+/// A string or byte array, unescaped.
+enum Escaped<'de>
+{
+    Borrowed(&'de [u8]),
+    #[cfg(feature = "alloc")]
+    Owned(Vec<u8>),
+}
+
+impl<'de> Deserializer<'de>
+{
+    pub fn from_slice(input: &'de [u8]) -> Self {
+        Self {
+            input,
+            offset: 0,
+            depth: 0,
+            max_depth: Config::default().max_depth,
+        }
+    }
+
+    /// Take the nesting limit of [`Config::max_depth`] from `config`. Keys have a single
+    /// encoding, so the other settings do not apply to them.
+    pub fn with_config(mut self, config: Config) -> Self {
+        self.max_depth = config.max_depth;
+        self
+    }
+
+    /// The input not consumed yet.
+    pub fn remaining(&self) -> &'de [u8] {
+        self.input
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'de [u8], Error> {
+        if self.input.len() < len {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "unexpected end of input").at(self.offset as u64));
+        }
+        let (head, tail) = self.input.split_at(len);
+        self.input = tail;
+        self.offset += len;
+        Ok(head)
+    }
+
+    fn read_array<const N: usize>(&mut self) -> Result<[u8; N], Error> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    fn read_u8(&mut self) -> Result<u8, Error> {
+        let [byte] = self.read_array()?;
+        Ok(byte)
+    }
+
+    fn read_u32(&mut self) -> Result<u32, Error> {
+        Ok(u32::from_be_bytes(self.read_array()?))
+    }
+
+    fn read_u64(&mut self) -> Result<u64, Error> {
+        Ok(u64::from_be_bytes(self.read_array()?))
+    }
+
+    /// Read one of two marker bytes, `false` for `no` and `true` for `yes`.
+    fn read_marker(&mut self, no: u8, yes: u8, what: &str) -> Result<bool, Error> {
+        let offset = self.offset;
+        match self.read_u8()? {
+            byte if byte == no => Ok(false),
+            byte if byte == yes => Ok(true),
+            _ => Err(Error::new(ErrorKind::InvalidTag, format_args!("invalid {} marker", what)).at(offset as u64)),
+        }
+    }
+
+    /// Read a string or byte array up to its end marker, borrowing it when nothing was escaped.
+    fn read_escaped(&mut self) -> Result<Escaped<'de>, Error> {
+        let invalid = |offset: usize| Error::new(ErrorKind::InvalidTag, "invalid escape in a string or byte array").at(offset as u64);
+        let end = |input: &[u8]| input.iter().position(|&byte| byte == 0);
+        let zero = end(self.input).ok_or_else(|| Error::new(ErrorKind::UnexpectedEof, "unexpected end of input").at((self.offset + self.input.len()) as u64))?;
+        match self.input.get(zero + 1) {
+            Some(0x00) => {
+                let bytes = self.take(zero)?;
+                self.take(END.len())?;
+                return Ok(Escaped::Borrowed(bytes));
+            }
+            Some(&ESCAPED) => {}
+            Some(_) => return Err(invalid(self.offset + zero)),
+            None => return Err(Error::new(ErrorKind::UnexpectedEof, "unexpected end of input").at((self.offset + zero + 1) as u64)),
+        }
+        #[cfg(feature = "alloc")]
+        {
+            let mut bytes = Vec::new();
+            loop {
+                let zero = end(self.input).ok_or_else(|| Error::new(ErrorKind::UnexpectedEof, "unexpected end of input").at((self.offset + self.input.len()) as u64))?;
+                bytes.extend_from_slice(self.take(zero)?);
+                let offset = self.offset;
+                match self.take(2)? {
+                    [0x00, 0x00] => return Ok(Escaped::Owned(bytes)),
+                    [0x00, ESCAPED] => bytes.push(0),
+                    _ => return Err(invalid(offset)),
+                }
+            }
+        }
+        #[cfg(not(feature = "alloc"))]
+        Err(Error::new(ErrorKind::Unsupported, "strings with zero bytes need the alloc feature").at(self.offset as u64))
+    }
+
+    fn nested<T>(&mut self, f: impl FnOnce(&mut Self) -> Result<T, Error>) -> Result<T, Error> {
+        if self.depth >= self.max_depth {
+            return Err(Error::new(ErrorKind::LimitExceeded, format_args!("nesting exceeds the depth limit of {}", self.max_depth)).at(self.offset as u64));
+        }
+        self.depth += 1;
+        let result = f(self);
+        self.depth -= 1;
+        result
+    }
+}
+
+impl<'de> serde::Deserializer<'de> for &mut Deserializer<'de> {
+    type Error = Error;
+
+    fn deserialize_any<V: serde::de::Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Self::Error> {
+        Err(Error::new(ErrorKind::Unsupported, "keys are not self-describing").at(self.offset as u64))
+    }
+
+    fn deserialize_bool<V: serde::de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_bool(self.read_marker(0x00, 0x01, "boolean")?)
+    }
+
+    fn deserialize_i8<V: serde::de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_i8((self.read_u8()? ^ (1 << 7)) as i8)
+    }
+
+    fn deserialize_i16<V: serde::de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_i16((u16::from_be_bytes(self.read_array()?) ^ (1 << 15)) as i16)
+    }
+
+    fn deserialize_i32<V: serde::de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_i32((self.read_u32()? ^ (1 << 31)) as i32)
+    }
+
+    fn deserialize_i64<V: serde::de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_i64((self.read_u64()? ^ (1 << 63)) as i64)
+    }
+
+    fn deserialize_i128<V: serde::de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_i128((u128::from_be_bytes(self.read_array()?) ^ (1 << 127)) as i128)
+    }
+
+    fn deserialize_u8<V: serde::de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_u8(self.read_u8()?)
+    }
+
+    fn deserialize_u16<V: serde::de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_u16(u16::from_be_bytes(self.read_array()?))
+    }
+
+    fn deserialize_u32<V: serde::de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_u32(self.read_u32()?)
+    }
+
+    fn deserialize_u64<V: serde::de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_u64(self.read_u64()?)
+    }
+
+    fn deserialize_u128<V: serde::de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_u128(u128::from_be_bytes(self.read_array()?))
+    }
+
+    fn deserialize_f32<V: serde::de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        let bits = self.read_u32()?;
+        visitor.visit_f32(f32::from_bits(if bits >> 31 == 1 { bits ^ (1 << 31) } else { !bits }))
+    }
+
+    fn deserialize_f64<V: serde::de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        let bits = self.read_u64()?;
+        visitor.visit_f64(f64::from_bits(if bits >> 63 == 1 { bits ^ (1 << 63) } else { !bits }))
+    }
+
+    fn deserialize_char<V: serde::de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        let offset = self.offset;
+        let c = char::from_u32(self.read_u32()?).ok_or_else(|| Error::new(ErrorKind::InvalidUtf8, "invalid char").at(offset as u64))?;
+        visitor.visit_char(c)
+    }
+
+    fn deserialize_str<V: serde::de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        let offset = self.offset as u64;
+        let invalid = || Error::new(ErrorKind::InvalidUtf8, "invalid utf-8").at(offset);
+        match self.read_escaped()? {
+            Escaped::Borrowed(bytes) => visitor.visit_borrowed_str(core::str::from_utf8(bytes).map_err(|_| invalid())?),
+            #[cfg(feature = "alloc")]
+            Escaped::Owned(bytes) => visitor.visit_string(String::from_utf8(bytes).map_err(|_| invalid())?),
+        }
+    }
+
+    fn deserialize_string<V: serde::de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_bytes<V: serde::de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match self.read_escaped()? {
+            Escaped::Borrowed(bytes) => visitor.visit_borrowed_bytes(bytes),
+            #[cfg(feature = "alloc")]
+            Escaped::Owned(bytes) => visitor.visit_byte_buf(bytes),
+        }
+    }
+
+    fn deserialize_byte_buf<V: serde::de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        self.deserialize_bytes(visitor)
+    }
+
+    fn deserialize_option<V: serde::de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        if self.read_marker(0x00, 0x01, "option")? {
+            self.nested(|de| visitor.visit_some(de))
+        } else {
+            visitor.visit_none()
+        }
+    }
+
+    fn deserialize_unit<V: serde::de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_unit_struct<V: serde::de::Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_newtype_struct<V: serde::de::Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value, Self::Error> {
+        self.nested(|de| visitor.visit_newtype_struct(de))
+    }
+
+    fn deserialize_seq<V: serde::de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        self.nested(|de| visitor.visit_seq(Elements { de, len: None }))
+    }
+
+    fn deserialize_tuple<V: serde::de::Visitor<'de>>(self, len: usize, visitor: V) -> Result<V::Value, Self::Error> {
+        self.nested(|de| visitor.visit_seq(Elements { de, len: Some(len) }))
+    }
+
+    fn deserialize_tuple_struct<V: serde::de::Visitor<'de>>(self, _name: &'static str, len: usize, visitor: V) -> Result<V::Value, Self::Error> {
+        self.deserialize_tuple(len, visitor)
+    }
+
+    fn deserialize_map<V: serde::de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        self.nested(|de| visitor.visit_map(Elements { de, len: None }))
+    }
+
+    fn deserialize_struct<V: serde::de::Visitor<'de>>(self, _name: &'static str, fields: &'static [&'static str], visitor: V) -> Result<V::Value, Self::Error> {
+        self.deserialize_tuple(fields.len(), visitor)
+    }
+
+    fn deserialize_enum<V: serde::de::Visitor<'de>>(self, _name: &'static str, _variants: &'static [&'static str], visitor: V) -> Result<V::Value, Self::Error> {
+        self.nested(|de| visitor.visit_enum(de))
+    }
+
+    fn deserialize_identifier<V: serde::de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        self.deserialize_u32(visitor)
+    }
+
+    fn deserialize_ignored_any<V: serde::de::Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Self::Error> {
+        Err(Error::new(ErrorKind::Unsupported, "keys cannot skip values").at(self.offset as u64))
+    }
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+}
+
+/// The elements of a sequence or map, each behind `ELEMENT`, or the fields of a tuple or struct.
+struct Elements<'a, 'de>
+{
+    de: &'a mut Deserializer<'de>,
+    /// Number of fields left, `None` when elements run until `LAST`.
+    len: Option<usize>,
+}
+
+impl<'a, 'de> Elements<'a, 'de>
+{
+    fn has_next(&mut self) -> Result<bool, Error> {
+        match &mut self.len {
+            Some(0) => Ok(false),
+            Some(len) => {
+                *len -= 1;
+                Ok(true)
+            }
+            None => self.de.read_marker(LAST, ELEMENT, "element"),
+        }
+    }
+}
+
+impl<'de, 'a> serde::de::SeqAccess<'de> for Elements<'a, 'de>
+{
+    type Error = Error;
+
+    fn next_element_seed<T: serde::de::DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>, Self::Error> {
+        if self.has_next()? {
+            seed.deserialize(&mut *self.de).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        self.len
+    }
+}
+
+impl<'de, 'a> serde::de::MapAccess<'de> for Elements<'a, 'de>
+{
+    type Error = Error;
+
+    fn next_key_seed<K: serde::de::DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>, Self::Error> {
+        if self.has_next()? {
+            seed.deserialize(&mut *self.de).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn next_value_seed<V: serde::de::DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Self::Error> {
+        seed.deserialize(&mut *self.de)
+    }
+}
+
+impl<'de> serde::de::EnumAccess<'de> for &mut Deserializer<'de>
+{
+    type Error = Error;
+    type Variant = Self;
+
+    fn variant_seed<V: serde::de::DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, Self::Variant), Self::Error> {
+        let offset = self.offset as u64;
+        let variant_index = self.read_u32()?;
+        let value = seed.deserialize(variant_index.into_deserializer()).map_err(|err: Error| err.at(offset))?;
+        Ok((value, self))
+    }
+}
+
+impl<'de> serde::de::VariantAccess<'de> for &mut Deserializer<'de>
+{
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn newtype_variant_seed<T: serde::de::DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, Self::Error> {
+        seed.deserialize(self)
+    }
+
+    fn tuple_variant<V: serde::de::Visitor<'de>>(self, len: usize, visitor: V) -> Result<V::Value, Self::Error> {
+        serde::Deserializer::deserialize_tuple(self, len, visitor)
+    }
+
+    fn struct_variant<V: serde::de::Visitor<'de>>(self, fields: &'static [&'static str], visitor: V) -> Result<V::Value, Self::Error> {
+        serde::Deserializer::deserialize_tuple(self, fields.len(), visitor)
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use core::cmp::Ordering;
+    use core::fmt::Debug;
+    use serde::de::DeserializeOwned;
+    use std::{string::{String, ToString}, vec, vec::Vec};
+
+    #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+    enum Shape {
+        Empty,
+        Point(i16),
+        Line(u8, String),
+        Named { id: Option<u32>, tag: Vec<u8> },
+    }
+
+    /// xorshift64, so that every run checks the same values.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: u64) -> u64 {
+            self.next() % n
+        }
+
+        /// Mostly small bytes, so that zeros and shared prefixes come up often.
+        fn bytes(&mut self) -> Vec<u8> {
+            (0..self.below(5)).map(|_| [0x00, 0x01, 0xff, self.next() as u8][self.below(4) as usize]).collect()
+        }
+
+        fn string(&mut self) -> String {
+            (0..self.below(5)).map(|_| ['\0', 'a', 'b', 'é', '\u{10ffff}'][self.below(5) as usize]).collect()
+        }
+
+        fn shape(&mut self) -> Shape {
+            match self.below(4) {
+                0 => Shape::Empty,
+                1 => Shape::Point(self.next() as i16),
+                2 => Shape::Line(self.below(3) as u8, self.string()),
+                _ => Shape::Named { id: if self.below(2) == 0 { None } else { Some(self.below(3) as u32) }, tag: self.bytes() },
+            }
+        }
+    }
+
+    /// Check every pair of `values`, including each value with itself, and that every key
+    /// decodes back.
+    fn check<T: Debug + Serialize + DeserializeOwned>(values: &[T], cmp: impl Fn(&T, &T) -> Ordering) {
+        let keys: Vec<Vec<u8>> = values.iter().map(|value| to_bytes(value).unwrap()).collect();
+        for (a, key_a) in values.iter().zip(&keys) {
+            for (b, key_b) in values.iter().zip(&keys) {
+                assert_eq!(key_a.cmp(key_b), cmp(a, b), "{:?} against {:?}", a, b);
+            }
+        }
+        for key in &keys {
+            let decoded: T = from_bytes(key).unwrap();
+            assert_eq!(to_bytes(&decoded).unwrap(), *key);
+        }
+    }
+
+    fn generate<T>(count: usize, mut f: impl FnMut(&mut Rng) -> T) -> Vec<T> {
+        let mut rng = Rng(0x9e3779b97f4a7c15);
+        (0..count).map(|_| f(&mut rng)).collect()
+    }
+
+    #[test]
+    fn integers_keep_their_order() {
+        let mut values = vec![i64::MIN, -1, 0, 1, i64::MAX];
+        values.extend(generate(200, |rng| rng.next() as i64 >> rng.below(64)));
+        check(&values, Ord::cmp);
+        let values: Vec<u16> = generate(200, |rng| rng.next() as u16).into_iter().chain([0, 1, u16::MAX]).collect();
+        check(&values, Ord::cmp);
+        check(&[i8::MIN, -1, 0, 1, i8::MAX], Ord::cmp);
+        check(&[0, 1, u128::MAX / 2, u128::MAX], Ord::cmp);
+        check(&[false, true], Ord::cmp);
+        check(&['\0', 'a', 'é', '\u{10ffff}'], Ord::cmp);
+    }
+
+    #[test]
+    fn floats_order_like_total_cmp() {
+        let mut values = vec![
+            f64::NEG_INFINITY, -f64::MAX, -1.5, -f64::MIN_POSITIVE, -0.0, 0.0, f64::MIN_POSITIVE, 1.5, f64::MAX,
+            f64::INFINITY, f64::NAN, -f64::NAN,
+        ];
+        values.extend(generate(100, |rng| f64::from_bits(rng.next())));
+        check(&values, f64::total_cmp);
+        assert!(to_bytes(&-0.0f64).unwrap() < to_bytes(&0.0f64).unwrap());
+        assert!(to_bytes(&f64::INFINITY).unwrap() < to_bytes(&f64::NAN).unwrap());
+        assert!(to_bytes(&-f64::NAN).unwrap() < to_bytes(&f64::NEG_INFINITY).unwrap());
+        let values = [f32::NEG_INFINITY, -1.0, -0.0, 0.0, 1.0, f32::INFINITY, f32::NAN, -f32::NAN];
+        check(&values, f32::total_cmp);
+    }
+
+    #[test]
+    fn strings_and_bytes_with_zeros_keep_their_order() {
+        let mut strings = vec![String::new(), "\0".into(), "\0\0".into(), "\0a".into(), "a".into(), "a\0".into(), "a\0b".into(), "ab".into()];
+        strings.extend(generate(100, Rng::string));
+        check(&strings, Ord::cmp);
+        let mut bytes = vec![vec![], vec![0x00], vec![0x00, 0x00], vec![0x00, 0xff], vec![0x01], vec![0xff, 0x00]];
+        bytes.extend(generate(100, Rng::bytes));
+        let bytes: Vec<serde_bytes_like::Bytes> = bytes.into_iter().map(serde_bytes_like::Bytes).collect();
+        check(&bytes, Ord::cmp);
+    }
+
+    #[test]
+    fn tuples_options_and_enums_keep_their_order() {
+        let tuples = generate(150, |rng| (rng.string(), rng.below(3) as u8, rng.bytes()));
+        check(&tuples, Ord::cmp);
+        let options = generate(100, |rng| if rng.below(3) == 0 { None } else { Some((rng.string(), rng.below(3) as i32 - 1)) });
+        check(&options, Ord::cmp);
+        let shapes = generate(200, Rng::shape);
+        check(&shapes, Ord::cmp);
+        let lists = generate(100, |rng| (0..rng.below(4)).map(|_| rng.shape()).collect::<Vec<_>>());
+        check(&lists, Ord::cmp);
+    }
+
+    /// `Vec<u8>` goes through `serialize_seq`; this goes through `serialize_bytes`.
+    mod serde_bytes_like {
+        use std::vec::Vec;
+
+        #[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+        pub struct Bytes(pub Vec<u8>);
+
+        impl serde::Serialize for Bytes {
+            fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.serialize_bytes(&self.0)
+            }
+        }
+
+        impl<'de> serde::Deserialize<'de> for Bytes {
+            fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                struct Visitor;
+
+                impl serde::de::Visitor<'_> for Visitor {
+                    type Value = Bytes;
+
+                    fn expecting(&self, formatter: &mut core::fmt::Formatter) -> core::fmt::Result {
+                        formatter.write_str("bytes")
+                    }
+
+                    fn visit_bytes<E>(self, bytes: &[u8]) -> Result<Bytes, E> {
+                        Ok(Bytes(bytes.to_vec()))
+                    }
+                }
+
+                deserializer.deserialize_byte_buf(Visitor)
+            }
+        }
+    }
+
+    #[test]
+    fn nesting_is_limited_by_the_config() {
+        let nested = to_bytes(&Some(Some(Some(1u8)))).unwrap();
+        let decode = |config: Config| Option::<Option<Option<u8>>>::deserialize(&mut Deserializer::from_slice(&nested).with_config(config));
+        assert_eq!(decode(Config::default()).unwrap(), Some(Some(Some(1))));
+        assert_eq!(decode(Config::default().max_depth(3)).unwrap(), Some(Some(Some(1))));
+        let err = decode(Config::default().max_depth(2)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::LimitExceeded);
+        assert!(err.to_string().contains("depth limit of 2"), "{}", err);
+    }
+}
@@ -0,0 +1,702 @@
+//! The shape of a type as seen by its `Deserialize` impl, for tools and for decoders that do not
+//! have the Rust type at hand.
+//!
+//! [`trace`] feeds placeholder values to `T::deserialize` and records every call it makes: struct
+//! names and fields, enum variants, tuple lengths and the types inside options, sequences and maps.
+//! An enum only reads one variant at a time, so `T` is traced over and over until every variant of
+//! every enum has been seen. A type that contains itself shows up as [`Schema::Recursive`] below
+//! its first occurrence.
+//!
+//! Named types are told apart by their name alone, so a generic type used with two different sets
+//! of parameters inside `T` fails to trace, as does a type whose `Deserialize` impl rejects the
+//! placeholders: one for integers, zero for floats and empty for strings, bytes and sequences.
+
+use serde::Serialize;
+use serde::Deserialize;
+use serde::de::IntoDeserializer;
+use super::alloc::{boxed::Box, collections::BTreeMap};
+
+use super::{vec, Error, ErrorKind, String, ToString, Vec};
+
+/// How deep the trace follows nested named types before it gives up.
+const MAX_TRACE_DEPTH: usize = 64;
+
+/// The serde shape of a type. It is serializable itself, so it can be stored next to the data it
+/// describes.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Schema {
+    Bool,
+    I8,
+    I16,
+    I32,
+    I64,
+    I128,
+    U8,
+    U16,
+    U32,
+    U64,
+    U128,
+    F32,
+    F64,
+    Char,
+    Str,
+    Bytes,
+    Unit,
+    /// Read through `deserialize_any`, so it could be anything the input describes.
+    Any,
+    Option(Box<Schema>),
+    Seq(Box<Schema>),
+    Map { key: Box<Schema>, value: Box<Schema> },
+    Tuple(Vec<Schema>),
+    UnitStruct(String),
+    NewtypeStruct(String, Box<Schema>),
+    TupleStruct(String, Vec<Schema>),
+    Struct(String, Vec<Field>),
+    /// The variants in declaration order, so a variant's position is its index.
+    Enum(String, Vec<Variant>),
+    /// The named type this is nested in, which contains itself.
+    Recursive(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Field {
+    pub name: String,
+    pub schema: Schema,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Variant {
+    pub name: String,
+    pub payload: Payload,
+}
+
+/// What an enum variant holds.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Payload {
+    Unit,
+    Newtype(Box<Schema>),
+    Tuple(Vec<Schema>),
+    Struct(Vec<Field>),
+}
+
+/// Trace the schema of `T`.
+pub fn trace<'de, T: Deserialize<'de>>() -> Result<Schema, Error> {
+    let mut tracer = Tracer {
+        containers: BTreeMap::new(),
+        enums: BTreeMap::new(),
+        stack: Vec::new(),
+        traced: false,
+        deferred: false,
+    };
+    loop {
+        tracer.stack.clear();
+        tracer.traced = false;
+        tracer.deferred = false;
+        let mut root = Schema::Any;
+        match T::deserialize(Trace { tracer: &mut tracer, schema: Some(&mut root) }) {
+            Ok(_) => match tracer.enums.iter().find(|(_, progress)| progress.payloads.contains(&None)) {
+                None => return Ok(tracer.inline(&root, &mut Vec::new())),
+                Some((name, _)) if !tracer.traced => {
+                    return Err(Error::new(ErrorKind::Unsupported, format_args!("the remaining variants of {} cannot be reached", name)));
+                }
+                Some(_) => {}
+            },
+            // Try again without the variant that failed, unless there is nothing left to try.
+            Err(err) if !tracer.deferred => return Err(err),
+            Err(_) => {}
+        }
+    }
+}
+
+/// What is known about an enum so far.
+struct Progress {
+    variants: &'static [&'static str],
+    /// Payloads of the variants traced so far.
+    payloads: Vec<Option<Payload>>,
+    /// Variants whose trace failed, passed over until another variant gets traced.
+    deferred: Vec<bool>,
+}
+
+/// State kept across the passes of a trace. Named types are recorded once in `containers` and
+/// `enums`, and schemas refer to them with [`Schema::Recursive`] until [`Tracer::inline`].
+struct Tracer {
+    containers: BTreeMap<&'static str, Schema>,
+    enums: BTreeMap<&'static str, Progress>,
+    /// Named types being traced.
+    stack: Vec<&'static str>,
+    /// Whether this pass traced a variant for the first time.
+    traced: bool,
+    /// Whether this pass failed and deferred a variant.
+    deferred: bool,
+}
+
+impl Tracer
+{
+    fn nested<T>(&mut self, name: &'static str, f: impl FnOnce(&mut Self) -> Result<T, Error>) -> Result<T, Error> {
+        if self.stack.len() >= MAX_TRACE_DEPTH {
+            return Err(Error::new(ErrorKind::LimitExceeded, format_args!("types are nested more than {} deep", MAX_TRACE_DEPTH)));
+        }
+        self.stack.push(name);
+        let result = f(self);
+        self.stack.pop();
+        result
+    }
+
+    fn register(&mut self, name: &'static str, schema: Schema) -> Result<(), Error> {
+        if self.enums.contains_key(name) || self.containers.get(name).is_some_and(|known| *known != schema) {
+            return Err(conflict(name));
+        }
+        self.containers.insert(name, schema);
+        Ok(())
+    }
+
+    /// The variant of the enum `name` to trace next: one not traced yet, else one that leads to an
+    /// enum with variants not traced yet. Types being traced further up are only placeholders
+    /// below, so the way there does not go through them.
+    fn choose(&self, name: &'static str) -> u32 {
+        let progress = &self.enums[name];
+        let untraced = |index: &usize| progress.payloads[*index].is_none();
+        let indices = 0..progress.payloads.len();
+        let index = indices.clone().find(|index| untraced(index) && !progress.deferred[*index])
+            .or_else(|| indices.clone().find(untraced))
+            .or_else(|| indices.clone().find(|index| progress.payloads[*index].as_ref().is_some_and(|payload| {
+                let mut seen = self.stack.clone();
+                seen.push(name);
+                self.payload_pending(payload, &mut seen)
+            })))
+            .unwrap_or(0);
+        index as u32
+    }
+
+    /// A variant of the enum `name` whose placeholder does not run into `name` again, preferably
+    /// one without a payload.
+    fn base(&self, name: &'static str) -> Option<u32> {
+        let payloads = &self.enums[name].payloads;
+        let base = payloads.iter().position(|payload| *payload == Some(Payload::Unit));
+        base.or_else(|| payloads.iter().position(|payload| payload.as_ref().is_some_and(|payload| self.payload_finite(payload, &mut vec![name]))))
+            .map(|index| index as u32)
+    }
+
+    /// Whether a placeholder for `schema` comes to an end, given that options, sequences and maps
+    /// are left empty and enums take their [`Tracer::base`].
+    fn finite<'a>(&'a self, schema: &'a Schema, seen: &mut Vec<&'a str>) -> bool {
+        match schema {
+            Schema::Recursive(name) => {
+                if seen.contains(&name.as_str()) {
+                    return false;
+                }
+                seen.push(name);
+                let finite = match (self.containers.get(name.as_str()), self.enums.get(name.as_str())) {
+                    (Some(schema), _) => self.finite(schema, seen),
+                    (None, Some(progress)) => progress.payloads.iter().flatten().any(|payload| self.payload_finite(payload, seen)),
+                    (None, None) => false,
+                };
+                seen.pop();
+                finite
+            }
+            Schema::NewtypeStruct(_, inner) => self.finite(inner, seen),
+            Schema::Tuple(elements) | Schema::TupleStruct(_, elements) => elements.iter().all(|element| self.finite(element, seen)),
+            Schema::Struct(_, fields) => fields.iter().all(|field| self.finite(&field.schema, seen)),
+            Schema::Enum(_, variants) => variants.iter().any(|variant| self.payload_finite(&variant.payload, seen)),
+            _ => true,
+        }
+    }
+
+    fn payload_finite<'a>(&'a self, payload: &'a Payload, seen: &mut Vec<&'a str>) -> bool {
+        match payload {
+            Payload::Unit => true,
+            Payload::Newtype(inner) => self.finite(inner, seen),
+            Payload::Tuple(elements) => elements.iter().all(|element| self.finite(element, seen)),
+            Payload::Struct(fields) => fields.iter().all(|field| self.finite(&field.schema, seen)),
+        }
+    }
+
+    /// Whether `schema` leads to an enum with variants not traced yet.
+    fn pending<'a>(&'a self, schema: &'a Schema, seen: &mut Vec<&'a str>) -> bool {
+        match schema {
+            Schema::Recursive(name) => {
+                if seen.contains(&name.as_str()) {
+                    return false;
+                }
+                seen.push(name);
+                if let Some(schema) = self.containers.get(name.as_str()) {
+                    return self.pending(schema, seen);
+                }
+                self.enums.get(name.as_str()).is_some_and(|progress| {
+                    progress.payloads.iter().any(|payload| match payload {
+                        Some(payload) => self.payload_pending(payload, seen),
+                        None => true,
+                    })
+                })
+            }
+            Schema::Option(inner) | Schema::Seq(inner) | Schema::NewtypeStruct(_, inner) => self.pending(inner, seen),
+            Schema::Map { key, value } => self.pending(key, seen) || self.pending(value, seen),
+            Schema::Tuple(elements) | Schema::TupleStruct(_, elements) => elements.iter().any(|element| self.pending(element, seen)),
+            Schema::Struct(_, fields) => fields.iter().any(|field| self.pending(&field.schema, seen)),
+            Schema::Enum(_, variants) => variants.iter().any(|variant| self.payload_pending(&variant.payload, seen)),
+            _ => false,
+        }
+    }
+
+    fn payload_pending<'a>(&'a self, payload: &'a Payload, seen: &mut Vec<&'a str>) -> bool {
+        match payload {
+            Payload::Unit => false,
+            Payload::Newtype(inner) => self.pending(inner, seen),
+            Payload::Tuple(elements) => elements.iter().any(|element| self.pending(element, seen)),
+            Payload::Struct(fields) => fields.iter().any(|field| self.pending(&field.schema, seen)),
+        }
+    }
+
+    /// Replace the references to named types in `schema` with their schemas, except inside
+    /// themselves.
+    fn inline(&self, schema: &Schema, outer: &mut Vec<String>) -> Schema {
+        match schema {
+            Schema::Recursive(name) if !outer.contains(name) => {
+                outer.push(name.clone());
+                let inlined = match (self.containers.get(name.as_str()), self.enums.get(name.as_str())) {
+                    (Some(schema), _) => self.inline(schema, outer),
+                    (None, Some(progress)) => Schema::Enum(name.clone(), progress.variants.iter().zip(&progress.payloads).map(|(variant, payload)| Variant {
+                        name: variant.to_string(),
+                        payload: match payload.as_ref().unwrap_or(&Payload::Unit) {
+                            Payload::Unit => Payload::Unit,
+                            Payload::Newtype(inner) => Payload::Newtype(Box::new(self.inline(inner, outer))),
+                            Payload::Tuple(elements) => Payload::Tuple(self.inline_all(elements, outer)),
+                            Payload::Struct(fields) => Payload::Struct(self.inline_fields(fields, outer)),
+                        },
+                    }).collect()),
+                    (None, None) => schema.clone(),
+                };
+                outer.pop();
+                inlined
+            }
+            Schema::Option(inner) => Schema::Option(Box::new(self.inline(inner, outer))),
+            Schema::Seq(inner) => Schema::Seq(Box::new(self.inline(inner, outer))),
+            Schema::Map { key, value } => Schema::Map { key: Box::new(self.inline(key, outer)), value: Box::new(self.inline(value, outer)) },
+            Schema::Tuple(elements) => Schema::Tuple(self.inline_all(elements, outer)),
+            Schema::NewtypeStruct(name, inner) => Schema::NewtypeStruct(name.clone(), Box::new(self.inline(inner, outer))),
+            Schema::TupleStruct(name, elements) => Schema::TupleStruct(name.clone(), self.inline_all(elements, outer)),
+            Schema::Struct(name, fields) => Schema::Struct(name.clone(), self.inline_fields(fields, outer)),
+            schema => schema.clone(),
+        }
+    }
+
+    fn inline_all(&self, schemas: &[Schema], outer: &mut Vec<String>) -> Vec<Schema> {
+        schemas.iter().map(|schema| self.inline(schema, outer)).collect()
+    }
+
+    fn inline_fields(&self, fields: &[Field], outer: &mut Vec<String>) -> Vec<Field> {
+        fields.iter().map(|field| Field { name: field.name.clone(), schema: self.inline(&field.schema, outer) }).collect()
+    }
+}
+
+fn conflict(name: &str) -> Error {
+    Error::new(ErrorKind::Unsupported, format_args!("two different types are named {}", name))
+}
+
+fn fields(names: &[&str], schemas: Vec<Schema>) -> Vec<Field> {
+    names.iter().zip(schemas).map(|(name, schema)| Field { name: name.to_string(), schema }).collect()
+}
+
+/// Hand `len` placeholder elements to `visitor`, and return their schemas if `keep` is set.
+fn visit_elements<'de, V: serde::de::Visitor<'de>>(tracer: &mut Tracer, keep: bool, len: usize, visitor: V) -> Result<(V::Value, Vec<Schema>), Error> {
+    let mut schemas = if keep { vec![Schema::Any; len] } else { Vec::new() };
+    let value = visitor.visit_seq(Elements {
+        tracer,
+        schemas: if keep { Some(&mut schemas) } else { None },
+        len,
+        next: 0,
+    })?;
+    Ok((value, schemas))
+}
+
+/// Trace the body of the named type `name` and leave a reference to it in `schema`.
+///
+/// If the type is already being traced further up, or no schema is wanted, `body` only has to
+/// produce a placeholder value and is told so.
+fn named<T>(tracer: &mut Tracer, name: &'static str, schema: Option<&mut Schema>, body: impl FnOnce(&mut Tracer, bool) -> Result<(T, Schema), Error>) -> Result<T, Error> {
+    let keep = schema.is_some() && !tracer.stack.contains(&name);
+    if let Some(schema) = schema {
+        *schema = Schema::Recursive(name.to_string());
+    }
+    let (value, body) = tracer.nested(name, |tracer| body(tracer, keep))?;
+    if keep {
+        tracer.register(name, body)?;
+    }
+    Ok(value)
+}
+
+/// Traces one value into `schema`, or only produces a placeholder value when there is none.
+///
+/// Placeholders skip everything they can: options are `None`, sequences and maps empty and enums
+/// take a variant that was traced already.
+struct Trace<'a>
+{
+    tracer: &'a mut Tracer,
+    schema: Option<&'a mut Schema>,
+}
+
+impl<'a> Trace<'a>
+{
+    fn set(&mut self, schema: Schema) {
+        if let Some(slot) = self.schema.as_deref_mut() {
+            *slot = schema;
+        }
+    }
+}
+
+/// Trace a primitive by recording its schema and visiting a placeholder value.
+macro_rules! trace_primitive {
+    ($($method:ident($schema:expr) => $visit:ident($($value:expr)?);)*) => {
+        $(
+            fn $method<V: serde::de::Visitor<'de>>(mut self, visitor: V) -> Result<V::Value, Self::Error> {
+                self.set($schema);
+                visitor.$visit($($value)?)
+            }
+        )*
+    };
+}
+
+impl<'de, 'a> serde::Deserializer<'de> for Trace<'a> {
+    type Error = Error;
+
+    trace_primitive! {
+        deserialize_any(Schema::Any) => visit_unit();
+        deserialize_bool(Schema::Bool) => visit_bool(false);
+        deserialize_i8(Schema::I8) => visit_i8(1);
+        deserialize_i16(Schema::I16) => visit_i16(1);
+        deserialize_i32(Schema::I32) => visit_i32(1);
+        deserialize_i64(Schema::I64) => visit_i64(1);
+        deserialize_i128(Schema::I128) => visit_i128(1);
+        deserialize_u8(Schema::U8) => visit_u8(1);
+        deserialize_u16(Schema::U16) => visit_u16(1);
+        deserialize_u32(Schema::U32) => visit_u32(1);
+        deserialize_u64(Schema::U64) => visit_u64(1);
+        deserialize_u128(Schema::U128) => visit_u128(1);
+        deserialize_f32(Schema::F32) => visit_f32(0.0);
+        deserialize_f64(Schema::F64) => visit_f64(0.0);
+        deserialize_char(Schema::Char) => visit_char('\0');
+        deserialize_str(Schema::Str) => visit_borrowed_str("");
+        deserialize_string(Schema::Str) => visit_borrowed_str("");
+        deserialize_bytes(Schema::Bytes) => visit_borrowed_bytes(b"");
+        deserialize_byte_buf(Schema::Bytes) => visit_borrowed_bytes(b"");
+        deserialize_unit(Schema::Unit) => visit_unit();
+        deserialize_identifier(Schema::Str) => visit_borrowed_str("");
+    }
+
+    fn deserialize_option<V: serde::de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        let Some(schema) = self.schema else {
+            return visitor.visit_none();
+        };
+        let mut inner = Schema::Any;
+        let value = visitor.visit_some(Trace { tracer: self.tracer, schema: Some(&mut inner) })?;
+        *schema = Schema::Option(Box::new(inner));
+        Ok(value)
+    }
+
+    fn deserialize_unit_struct<V: serde::de::Visitor<'de>>(mut self, name: &'static str, visitor: V) -> Result<V::Value, Self::Error> {
+        self.set(Schema::UnitStruct(name.to_string()));
+        visitor.visit_unit()
+    }
+
+    fn deserialize_newtype_struct<V: serde::de::Visitor<'de>>(self, name: &'static str, visitor: V) -> Result<V::Value, Self::Error> {
+        named(self.tracer, name, self.schema, |tracer, keep| {
+            let mut inner = Schema::Any;
+            let value = visitor.visit_newtype_struct(Trace { tracer, schema: keep.then_some(&mut inner) })?;
+            Ok((value, Schema::NewtypeStruct(name.to_string(), Box::new(inner))))
+        })
+    }
+
+    fn deserialize_seq<V: serde::de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        let Some(schema) = self.schema else {
+            return visitor.visit_seq(Elements { tracer: self.tracer, schemas: None, len: 0, next: 0 });
+        };
+        let mut slots = [Schema::Any];
+        let value = visitor.visit_seq(Elements { tracer: self.tracer, schemas: Some(&mut slots), len: 1, next: 0 })?;
+        let [element] = slots;
+        *schema = Schema::Seq(Box::new(element));
+        Ok(value)
+    }
+
+    fn deserialize_tuple<V: serde::de::Visitor<'de>>(self, len: usize, visitor: V) -> Result<V::Value, Self::Error> {
+        let (value, elements) = visit_elements(self.tracer, self.schema.is_some(), len, visitor)?;
+        if let Some(schema) = self.schema {
+            *schema = Schema::Tuple(elements);
+        }
+        Ok(value)
+    }
+
+    fn deserialize_tuple_struct<V: serde::de::Visitor<'de>>(self, name: &'static str, len: usize, visitor: V) -> Result<V::Value, Self::Error> {
+        named(self.tracer, name, self.schema, |tracer, keep| {
+            let (value, elements) = visit_elements(tracer, keep, len, visitor)?;
+            Ok((value, Schema::TupleStruct(name.to_string(), elements)))
+        })
+    }
+
+    fn deserialize_map<V: serde::de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        let Some(schema) = self.schema else {
+            return visitor.visit_map(Elements { tracer: self.tracer, schemas: None, len: 0, next: 0 });
+        };
+        let mut slots = [Schema::Any, Schema::Any];
+        let value = visitor.visit_map(Elements { tracer: self.tracer, schemas: Some(&mut slots), len: 2, next: 0 })?;
+        let [key, value_schema] = slots;
+        *schema = Schema::Map { key: Box::new(key), value: Box::new(value_schema) };
+        Ok(value)
+    }
+
+    fn deserialize_struct<V: serde::de::Visitor<'de>>(self, name: &'static str, names: &'static [&'static str], visitor: V) -> Result<V::Value, Self::Error> {
+        named(self.tracer, name, self.schema, |tracer, keep| {
+            let (value, schemas) = visit_elements(tracer, keep, names.len(), visitor)?;
+            Ok((value, Schema::Struct(name.to_string(), fields(names, schemas))))
+        })
+    }
+
+    fn deserialize_enum<V: serde::de::Visitor<'de>>(self, name: &'static str, variants: &'static [&'static str], visitor: V) -> Result<V::Value, Self::Error> {
+        let tracer = self.tracer;
+        if variants.is_empty() {
+            return Err(Error::new(ErrorKind::Unsupported, format_args!("enum {} has no variants", name)));
+        }
+        if tracer.containers.contains_key(name) {
+            return Err(conflict(name));
+        }
+        let progress = tracer.enums.entry(name).or_insert_with(|| Progress {
+            variants,
+            payloads: vec![None; variants.len()],
+            deferred: vec![false; variants.len()],
+        });
+        if progress.variants != variants {
+            return Err(conflict(name));
+        }
+
+        let keep = self.schema.is_some() && !tracer.stack.contains(&name);
+        if let Some(schema) = self.schema {
+            *schema = Schema::Recursive(name.to_string());
+        }
+        if !keep {
+            // Without a variant known to work, a placeholder that goes around in circles runs into
+            // the depth limit, and the variant further up that led here is deferred.
+            let index = tracer.base(name).unwrap_or_else(|| tracer.choose(name));
+            return tracer.nested(name, |tracer| visitor.visit_enum(Variants { tracer, index, payload: None }));
+        }
+
+        let index = tracer.choose(name);
+        let mut payload = Payload::Unit;
+        let result = tracer.nested(name, |tracer| visitor.visit_enum(Variants { tracer, index, payload: Some(&mut payload) }));
+        let progress = tracer.enums.get_mut(name).unwrap();
+        let slot = &mut progress.payloads[index as usize];
+        match result {
+            Ok(value) => {
+                match slot {
+                    Some(known) if *known != payload => return Err(conflict(name)),
+                    Some(_) => {}
+                    None => {
+                        *slot = Some(payload);
+                        tracer.traced = true;
+                        for progress in tracer.enums.values_mut() {
+                            progress.deferred.fill(false);
+                        }
+                    }
+                }
+                Ok(value)
+            }
+            Err(err) => {
+                // Only the innermost variant that has not been traced yet is to blame.
+                if !tracer.deferred && slot.is_none() && !progress.deferred[index as usize] {
+                    progress.deferred[index as usize] = true;
+                    tracer.deferred = true;
+                }
+                Err(err)
+            }
+        }
+    }
+
+    fn deserialize_ignored_any<V: serde::de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_unit()
+    }
+}
+
+/// The placeholder elements of a sequence, tuple, struct or map, whose keys and values take
+/// turns.
+struct Elements<'a>
+{
+    tracer: &'a mut Tracer,
+    /// One per element, unless only placeholders are wanted.
+    schemas: Option<&'a mut [Schema]>,
+    len: usize,
+    next: usize,
+}
+
+impl<'a> Elements<'a>
+{
+    fn element<'de, T: serde::de::DeserializeSeed<'de>>(&mut self, seed: T) -> Result<T::Value, Error> {
+        let next = self.next;
+        self.next += 1;
+        let schema = self.schemas.as_deref_mut().map(|schemas| &mut schemas[next]);
+        seed.deserialize(Trace { tracer: &mut *self.tracer, schema })
+    }
+}
+
+impl<'de, 'a> serde::de::SeqAccess<'de> for Elements<'a>
+{
+    type Error = Error;
+
+    fn next_element_seed<T: serde::de::DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>, Self::Error> {
+        if self.next == self.len {
+            return Ok(None);
+        }
+        self.element(seed).map(Some)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.len - self.next)
+    }
+}
+
+impl<'de, 'a> serde::de::MapAccess<'de> for Elements<'a>
+{
+    type Error = Error;
+
+    fn next_key_seed<K: serde::de::DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>, Self::Error> {
+        serde::de::SeqAccess::next_element_seed(self, seed)
+    }
+
+    fn next_value_seed<V: serde::de::DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Self::Error> {
+        self.element(seed)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some((self.len - self.next) / 2)
+    }
+}
+
+/// The variant `index` of an enum, with the schema of its payload going to `payload` unless only
+/// a placeholder is wanted.
+struct Variants<'a, 'b>
+{
+    tracer: &'a mut Tracer,
+    index: u32,
+    payload: Option<&'b mut Payload>,
+}
+
+impl<'de, 'a, 'b> serde::de::EnumAccess<'de> for Variants<'a, 'b>
+{
+    type Error = Error;
+    type Variant = Self;
+
+    fn variant_seed<V: serde::de::DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, Self::Variant), Self::Error> {
+        let variant: serde::de::value::U32Deserializer<Error> = self.index.into_deserializer();
+        let value = seed.deserialize(variant)?;
+        Ok((value, self))
+    }
+}
+
+impl<'de, 'a, 'b> serde::de::VariantAccess<'de> for Variants<'a, 'b>
+{
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn newtype_variant_seed<T: serde::de::DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, Self::Error> {
+        let Some(payload) = self.payload else {
+            return seed.deserialize(Trace { tracer: self.tracer, schema: None });
+        };
+        let mut inner = Schema::Any;
+        let value = seed.deserialize(Trace { tracer: self.tracer, schema: Some(&mut inner) })?;
+        *payload = Payload::Newtype(Box::new(inner));
+        Ok(value)
+    }
+
+    fn tuple_variant<V: serde::de::Visitor<'de>>(self, len: usize, visitor: V) -> Result<V::Value, Self::Error> {
+        let (value, elements) = visit_elements(self.tracer, self.payload.is_some(), len, visitor)?;
+        if let Some(payload) = self.payload {
+            *payload = Payload::Tuple(elements);
+        }
+        Ok(value)
+    }
+
+    fn struct_variant<V: serde::de::Visitor<'de>>(self, names: &'static [&'static str], visitor: V) -> Result<V::Value, Self::Error> {
+        let (value, schemas) = visit_elements(self.tracer, self.payload.is_some(), names.len(), visitor)?;
+        if let Some(payload) = self.payload {
+            *payload = Payload::Struct(fields(names, schemas));
+        }
+        Ok(value)
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use core::num::NonZeroU8;
+
+    #[derive(Deserialize)]
+    #[allow(dead_code)]
+    enum Expr {
+        Lit(i64),
+        Neg(Box<Expr>),
+        Add(Box<Expr>, Box<Expr>),
+        Call { name: String, args: Vec<Expr> },
+    }
+
+    #[derive(Deserialize)]
+    #[allow(dead_code)]
+    struct Wrapper<T>(T);
+
+    #[derive(Deserialize)]
+    #[allow(dead_code)]
+    struct Mixed {
+        small: Wrapper<u8>,
+        large: Wrapper<u64>,
+    }
+
+    fn field(name: &str, schema: Schema) -> Field {
+        Field { name: name.into(), schema }
+    }
+
+    #[test]
+    fn traces_structs_and_primitives() {
+        #[derive(Deserialize)]
+        #[allow(dead_code)]
+        struct Record {
+            id: NonZeroU8,
+            tags: Vec<(String, Option<f32>)>,
+            extra: std::collections::BTreeMap<char, bool>,
+        }
+
+        let tags = Schema::Seq(Box::new(Schema::Tuple(vec![Schema::Str, Schema::Option(Box::new(Schema::F32))])));
+        let extra = Schema::Map { key: Box::new(Schema::Char), value: Box::new(Schema::Bool) };
+        assert_eq!(
+            trace::<Record>().unwrap(),
+            Schema::Struct("Record".into(), vec![field("id", Schema::U8), field("tags", tags), field("extra", extra)]),
+        );
+    }
+
+    #[test]
+    fn traces_every_variant_of_a_recursive_enum() {
+        let Schema::Enum(name, variants) = trace::<Expr>().unwrap() else {
+            panic!("not an enum");
+        };
+        assert_eq!(name, "Expr");
+        let recursive = || Box::new(Schema::Recursive("Expr".into()));
+        let payloads: Vec<_> = variants.into_iter().map(|variant| (variant.name, variant.payload)).collect();
+        assert_eq!(payloads, [
+            ("Lit".into(), Payload::Newtype(Box::new(Schema::I64))),
+            ("Neg".into(), Payload::Newtype(recursive())),
+            ("Add".into(), Payload::Tuple(vec![*recursive(), *recursive()])),
+            ("Call".into(), Payload::Struct(vec![field("name", Schema::Str), field("args", Schema::Seq(recursive()))])),
+        ]);
+    }
+
+    #[test]
+    fn traced_schemas_round_trip() {
+        let schema = trace::<Expr>().unwrap();
+        let bytes = super::super::to_bytes(&schema).unwrap();
+        assert_eq!(super::super::from_bytes::<Schema>(&bytes).unwrap(), schema);
+    }
+
+    #[test]
+    fn generics_with_different_parameters_conflict() {
+        assert!(trace::<Wrapper<u8>>().is_ok());
+        assert!(trace::<Mixed>().is_err());
+    }
+}
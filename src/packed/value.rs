@@ -0,0 +1,1064 @@
+//! A packed document held in memory without a Rust type for it.
+//!
+//! [`to_value`] and [`from_value`] convert between [`Value`] and any serde type. [`to_value`]
+//! keeps tuples, structs and enum variants apart from sequences and maps, so a `Value` serializes
+//! with the same calls as the data it was made from and encodes to the same bytes.
+//!
+//! Decoding into a `Value` goes through `deserialize_any`, so it needs input that describes itself,
+//! such as packed data in [`Config::self_describing`](super::Config::self_describing) mode; compact
+//! input fails with [`ErrorKind::Unsupported`]. Such input does not tell structs from maps or
+//! tuples from sequences, so structs come in as maps keyed by field name, tuples as sequences and
+//! enum variants as maps with a single entry.
+
+use serde::Serialize;
+use serde::Deserialize;
+use serde::de::IntoDeserializer;
+use serde::ser::{SerializeStruct, SerializeStructVariant, SerializeTuple, SerializeTupleVariant};
+use super::alloc::{borrow::Cow, boxed::Box};
+
+use super::{Error, ErrorKind, String, ToString, Vec};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Unit,
+    Bool(bool),
+    U8(u8),
+    U16(u16),
+    U32(u32),
+    U64(u64),
+    U128(u128),
+    I8(i8),
+    I16(i16),
+    I32(i32),
+    I64(i64),
+    I128(i128),
+    F32(f32),
+    F64(f64),
+    Char(char),
+    String(String),
+    Bytes(Vec<u8>),
+    Option(Option<Box<Value>>),
+    Seq(Vec<Value>),
+    /// A tuple, tuple struct or array, which unlike a `Seq` has no length in compact mode.
+    Tuple(Vec<Value>),
+    /// Entries in the order they were written.
+    Map(Vec<(Value, Value)>),
+    /// Fields of a struct in order, with `None` for a field that was skipped, which
+    /// [`StructEncoding::Presence`](super::StructEncoding::Presence) still writes a bit for. A
+    /// struct with an owned field name is written as a map of the fields that are there.
+    Struct(Vec<(Cow<'static, str>, Option<Value>)>),
+    /// An enum variant by index and name. A variant with an owned name is written the way a
+    /// variant from self-describing input reads back into a `Value`: a unit variant as its name,
+    /// any other as a map with a single entry from its name to its payload.
+    UnitVariant(u32, Cow<'static, str>),
+    NewtypeVariant(u32, Cow<'static, str>, Box<Value>),
+    TupleVariant(u32, Cow<'static, str>, Vec<Value>),
+    StructVariant(u32, Cow<'static, str>, Vec<(Cow<'static, str>, Option<Value>)>),
+}
+
+/// Convert `value` into a [`Value`] through its `Serialize` impl.
+pub fn to_value<T: ?Sized + Serialize>(value: &T) -> Result<Value, Error> {
+    value.serialize(ValueSerializer)
+}
+
+/// Build a `T` out of `value` through its `Deserialize` impl.
+pub fn from_value<T: serde::de::DeserializeOwned>(value: Value) -> Result<T, Error> {
+    T::deserialize(value)
+}
+
+/// Build a [`Value`] from a literal, mostly for tests.
+///
+/// `()`, `None`, `Some(..)`, `[..]` and `{ key => value, .. }` build the unit, options, sequences
+/// and maps, `enum(index, "name")` a unit variant, `enum(index, "name", payload)` a newtype
+/// variant, and anything else goes through
+/// `Value::from`, so integers need a suffix unless they are `i32`.
+///
+/// ```ignore
+/// let value = packed_value!({ "id" => 7u32, "tags" => ["a", "b"], "parent" => None });
+/// ```
+#[macro_export]
+macro_rules! packed_value {
+    (()) => {
+        $crate::packed::Value::Unit
+    };
+    (None) => {
+        $crate::packed::Value::Option(::core::option::Option::None)
+    };
+    (Some($($inner:tt)+)) => {
+        $crate::packed::Value::from(::core::option::Option::Some($crate::packed_value!($($inner)+)))
+    };
+    ([]) => {
+        $crate::packed::Value::Seq(::core::default::Default::default())
+    };
+    ([$($elements:tt)+]) => {
+        $crate::packed_value!(@seq [] [] $($elements)+)
+    };
+    ({}) => {
+        $crate::packed::Value::Map(::core::default::Default::default())
+    };
+    ({$($entries:tt)+}) => {
+        $crate::packed_value!(@map [] [] $($entries)+)
+    };
+    (enum($index:expr, $name:expr)) => {
+        $crate::packed::Value::UnitVariant($index, ::core::convert::From::from($name))
+    };
+    (enum($index:expr, $name:expr, $($payload:tt)+)) => {
+        $crate::packed::Value::NewtypeVariant($index, ::core::convert::From::from($name), ::core::convert::From::from($crate::packed_value!($($payload)+)))
+    };
+
+    // Split elements at the commas, collecting the tokens of the current one in the second list.
+    (@seq [$($done:tt)*] [$($current:tt)+] , $($rest:tt)*) => {
+        $crate::packed_value!(@seq [$($done)* ($crate::packed_value!($($current)+)),] [] $($rest)*)
+    };
+    (@seq [$($done:tt)*] [$($current:tt)*] $next:tt $($rest:tt)*) => {
+        $crate::packed_value!(@seq [$($done)*] [$($current)* $next] $($rest)*)
+    };
+    (@seq [$($done:tt)*] []) => {
+        $crate::packed::Value::from([$($done)*])
+    };
+    (@seq [$($done:tt)*] [$($current:tt)+]) => {
+        $crate::packed::Value::from([$($done)* ($crate::packed_value!($($current)+))])
+    };
+
+    // Entries are split at `=>` and then at the comma, the key is carried along in parentheses.
+    (@map [$($done:tt)*] [$($key:tt)+] => $($rest:tt)*) => {
+        $crate::packed_value!(@map [$($done)*] ($($key)+) [] $($rest)*)
+    };
+    (@map [$($done:tt)*] [$($key:tt)*] $next:tt $($rest:tt)*) => {
+        $crate::packed_value!(@map [$($done)*] [$($key)* $next] $($rest)*)
+    };
+    (@map [$($done:tt)*] ($($key:tt)+) [$($current:tt)+] , $($rest:tt)*) => {
+        $crate::packed_value!(@map [$($done)* ($crate::packed_value!($($key)+), $crate::packed_value!($($current)+)),] [] $($rest)*)
+    };
+    (@map [$($done:tt)*] ($($key:tt)+) [$($current:tt)*] $next:tt $($rest:tt)*) => {
+        $crate::packed_value!(@map [$($done)*] ($($key)+) [$($current)* $next] $($rest)*)
+    };
+    (@map [$($done:tt)*] []) => {
+        $crate::packed::Value::from([$($done)*])
+    };
+    (@map [$($done:tt)*] ($($key:tt)+) [$($current:tt)+]) => {
+        $crate::packed::Value::from([$($done)* ($crate::packed_value!($($key)+), $crate::packed_value!($($current)+))])
+    };
+
+    ($other:expr) => {
+        $crate::packed::Value::from($other)
+    };
+}
+
+macro_rules! value_from {
+    ($($ty:ty => $variant:ident;)*) => {
+        $(
+            impl From<$ty> for Value
+            {
+                fn from(v: $ty) -> Self {
+                    Value::$variant(v.into())
+                }
+            }
+        )*
+    };
+}
+
+value_from! {
+    bool => Bool;
+    u8 => U8;
+    u16 => U16;
+    u32 => U32;
+    u64 => U64;
+    u128 => U128;
+    i8 => I8;
+    i16 => I16;
+    i32 => I32;
+    i64 => I64;
+    i128 => I128;
+    f32 => F32;
+    f64 => F64;
+    char => Char;
+    String => String;
+    &str => String;
+    Vec<u8> => Bytes;
+    &[u8] => Bytes;
+    Vec<Value> => Seq;
+    Vec<(Value, Value)> => Map;
+}
+
+impl From<()> for Value
+{
+    fn from((): ()) -> Self {
+        Value::Unit
+    }
+}
+
+impl<const N: usize> From<&[u8; N]> for Value
+{
+    fn from(v: &[u8; N]) -> Self {
+        Value::Bytes(v.to_vec())
+    }
+}
+
+impl<const N: usize> From<[Value; N]> for Value
+{
+    fn from(v: [Value; N]) -> Self {
+        Value::Seq(v.into())
+    }
+}
+
+impl<const N: usize> From<[(Value, Value); N]> for Value
+{
+    fn from(v: [(Value, Value); N]) -> Self {
+        Value::Map(v.into())
+    }
+}
+
+impl From<Option<Value>> for Value
+{
+    fn from(v: Option<Value>) -> Self {
+        Value::Option(v.map(Box::new))
+    }
+}
+
+impl Value
+{
+    fn unexpected(&self) -> serde::de::Unexpected<'_> {
+        use serde::de::Unexpected;
+        match self {
+            Value::Unit => Unexpected::Unit,
+            Value::Bool(v) => Unexpected::Bool(*v),
+            Value::U8(v) => Unexpected::Unsigned(*v as u64),
+            Value::U16(v) => Unexpected::Unsigned(*v as u64),
+            Value::U32(v) => Unexpected::Unsigned(*v as u64),
+            Value::U64(v) => Unexpected::Unsigned(*v),
+            Value::U128(_) => Unexpected::Other("u128"),
+            Value::I8(v) => Unexpected::Signed(*v as i64),
+            Value::I16(v) => Unexpected::Signed(*v as i64),
+            Value::I32(v) => Unexpected::Signed(*v as i64),
+            Value::I64(v) => Unexpected::Signed(*v),
+            Value::I128(_) => Unexpected::Other("i128"),
+            Value::F32(v) => Unexpected::Float(*v as f64),
+            Value::F64(v) => Unexpected::Float(*v),
+            Value::Char(v) => Unexpected::Char(*v),
+            Value::String(v) => Unexpected::Str(v),
+            Value::Bytes(v) => Unexpected::Bytes(v),
+            Value::Option(_) => Unexpected::Option,
+            Value::Seq(_) | Value::Tuple(_) => Unexpected::Seq,
+            Value::Map(_) | Value::Struct(_) => Unexpected::Map,
+            Value::UnitVariant(..) => Unexpected::UnitVariant,
+            Value::NewtypeVariant(..) => Unexpected::NewtypeVariant,
+            Value::TupleVariant(..) => Unexpected::TupleVariant,
+            Value::StructVariant(..) => Unexpected::StructVariant,
+        }
+    }
+
+    fn invalid_type(&self, expected: &dyn serde::de::Expected) -> Error {
+        serde::de::Error::invalid_type(self.unexpected(), expected)
+    }
+}
+
+impl Serialize for Value
+{
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Value::Unit => serializer.serialize_unit(),
+            Value::Bool(v) => serializer.serialize_bool(*v),
+            Value::U8(v) => serializer.serialize_u8(*v),
+            Value::U16(v) => serializer.serialize_u16(*v),
+            Value::U32(v) => serializer.serialize_u32(*v),
+            Value::U64(v) => serializer.serialize_u64(*v),
+            Value::U128(v) => serializer.serialize_u128(*v),
+            Value::I8(v) => serializer.serialize_i8(*v),
+            Value::I16(v) => serializer.serialize_i16(*v),
+            Value::I32(v) => serializer.serialize_i32(*v),
+            Value::I64(v) => serializer.serialize_i64(*v),
+            Value::I128(v) => serializer.serialize_i128(*v),
+            Value::F32(v) => serializer.serialize_f32(*v),
+            Value::F64(v) => serializer.serialize_f64(*v),
+            Value::Char(v) => serializer.serialize_char(*v),
+            Value::String(v) => serializer.serialize_str(v),
+            Value::Bytes(v) => serializer.serialize_bytes(v),
+            Value::Option(None) => serializer.serialize_none(),
+            Value::Option(Some(v)) => serializer.serialize_some(v),
+            Value::Seq(v) => serializer.collect_seq(v),
+            Value::Tuple(v) => Elements(v).serialize(serializer),
+            Value::Map(v) => serializer.collect_map(v.iter().map(|(key, value)| (key, value))),
+            Value::Struct(v) => Fields(v).serialize(serializer),
+            Value::UnitVariant(index, name) => match name {
+                Cow::Borrowed(name) => serializer.serialize_unit_variant("Value", *index, name),
+                Cow::Owned(name) => serializer.serialize_str(name),
+            },
+            Value::NewtypeVariant(index, name, payload) => match name {
+                Cow::Borrowed(name) => serializer.serialize_newtype_variant("Value", *index, name, payload),
+                Cow::Owned(name) => serializer.collect_map([(name, payload)]),
+            },
+            Value::TupleVariant(index, name, elements) => match name {
+                Cow::Borrowed(name) => {
+                    let mut variant = serializer.serialize_tuple_variant("Value", *index, name, elements.len())?;
+                    for element in elements {
+                        variant.serialize_field(element)?;
+                    }
+                    variant.end()
+                }
+                Cow::Owned(name) => serializer.collect_map([(name, Elements(elements))]),
+            },
+            Value::StructVariant(index, name, fields) => match (name, static_fields(fields)) {
+                (Cow::Borrowed(name), Some(static_fields)) => {
+                    let mut variant = serializer.serialize_struct_variant("Value", *index, name, present(fields))?;
+                    for (key, value) in static_fields {
+                        match value {
+                            Some(value) => variant.serialize_field(key, value)?,
+                            None => variant.skip_field(key)?,
+                        }
+                    }
+                    variant.end()
+                }
+                _ => serializer.collect_map([(name, Fields(fields))]),
+            },
+        }
+    }
+}
+
+/// The elements of a [`Value::Tuple`] or [`Value::TupleVariant`], written as a tuple.
+struct Elements<'a>(&'a [Value]);
+
+impl Serialize for Elements<'_>
+{
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut tuple = serializer.serialize_tuple(self.0.len())?;
+        for element in self.0 {
+            tuple.serialize_element(element)?;
+        }
+        tuple.end()
+    }
+}
+
+/// The fields of a [`Value::Struct`] or [`Value::StructVariant`], written as a struct if all their
+/// names are `'static` and as a map otherwise.
+struct Fields<'a>(&'a [(Cow<'static, str>, Option<Value>)]);
+
+impl Serialize for Fields<'_>
+{
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let Some(fields) = static_fields(self.0) else {
+            return serializer.collect_map(self.0.iter().filter_map(|(key, value)| Some((key, value.as_ref()?))));
+        };
+        let mut state = serializer.serialize_struct("Value", present(self.0))?;
+        for (key, value) in fields {
+            match value {
+                Some(value) => state.serialize_field(key, value)?,
+                None => state.skip_field(key)?,
+            }
+        }
+        state.end()
+    }
+}
+
+/// The fields with their names, if none of the names is owned.
+fn static_fields<'a>(fields: &'a [(Cow<'static, str>, Option<Value>)]) -> Option<impl Iterator<Item = (&'static str, Option<&'a Value>)>> {
+    let borrowed = fields.iter().all(|(key, _)| matches!(key, Cow::Borrowed(_)));
+    borrowed.then(|| fields.iter().filter_map(|(key, value)| match key {
+        Cow::Borrowed(key) => Some((*key, value.as_ref())),
+        Cow::Owned(_) => None,
+    }))
+}
+
+/// How many of the fields were not skipped, the length a derived `Serialize` passes along.
+fn present(fields: &[(Cow<'static, str>, Option<Value>)]) -> usize {
+    fields.iter().filter(|(_, value)| value.is_some()).count()
+}
+
+impl<'de> Deserialize<'de> for Value
+{
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(ValueVisitor)
+    }
+}
+
+struct ValueVisitor;
+
+macro_rules! visit_value {
+    ($($method:ident($ty:ty) => $variant:ident;)*) => {
+        $(
+            fn $method<E: serde::de::Error>(self, v: $ty) -> Result<Value, E> {
+                Ok(Value::$variant(v.into()))
+            }
+        )*
+    };
+}
+
+impl<'de> serde::de::Visitor<'de> for ValueVisitor
+{
+    type Value = Value;
+
+    fn expecting(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.write_str("any value")
+    }
+
+    visit_value! {
+        visit_bool(bool) => Bool;
+        visit_u8(u8) => U8;
+        visit_u16(u16) => U16;
+        visit_u32(u32) => U32;
+        visit_u64(u64) => U64;
+        visit_u128(u128) => U128;
+        visit_i8(i8) => I8;
+        visit_i16(i16) => I16;
+        visit_i32(i32) => I32;
+        visit_i64(i64) => I64;
+        visit_i128(i128) => I128;
+        visit_f32(f32) => F32;
+        visit_f64(f64) => F64;
+        visit_char(char) => Char;
+        visit_str(&str) => String;
+        visit_string(String) => String;
+        visit_bytes(&[u8]) => Bytes;
+        visit_byte_buf(Vec<u8>) => Bytes;
+    }
+
+    fn visit_unit<E: serde::de::Error>(self) -> Result<Value, E> {
+        Ok(Value::Unit)
+    }
+
+    fn visit_none<E: serde::de::Error>(self) -> Result<Value, E> {
+        Ok(Value::Option(None))
+    }
+
+    fn visit_some<D: serde::Deserializer<'de>>(self, deserializer: D) -> Result<Value, D::Error> {
+        Ok(Value::Option(Some(Box::new(Value::deserialize(deserializer)?))))
+    }
+
+    fn visit_newtype_struct<D: serde::Deserializer<'de>>(self, deserializer: D) -> Result<Value, D::Error> {
+        Value::deserialize(deserializer)
+    }
+
+    fn visit_seq<A: serde::de::SeqAccess<'de>>(self, mut seq: A) -> Result<Value, A::Error> {
+        // Do not trust the size hint of the input with the allocation.
+        let mut elements = Vec::with_capacity(seq.size_hint().unwrap_or(0).min(4096));
+        while let Some(element) = seq.next_element()? {
+            elements.push(element);
+        }
+        Ok(Value::Seq(elements))
+    }
+
+    fn visit_map<A: serde::de::MapAccess<'de>>(self, mut map: A) -> Result<Value, A::Error> {
+        let mut entries = Vec::with_capacity(map.size_hint().unwrap_or(0).min(4096));
+        while let Some(entry) = map.next_entry()? {
+            entries.push(entry);
+        }
+        Ok(Value::Map(entries))
+    }
+}
+
+/// Turns serde data into a [`Value`].
+struct ValueSerializer;
+
+impl serde::Serializer for ValueSerializer
+{
+    type Ok = Value;
+    type Error = Error;
+
+    type SerializeSeq = SeqBuilder;
+    type SerializeTuple = SeqBuilder;
+    type SerializeTupleStruct = SeqBuilder;
+    type SerializeTupleVariant = SeqBuilder;
+    type SerializeMap = MapBuilder;
+    type SerializeStruct = StructBuilder;
+    type SerializeStructVariant = StructBuilder;
+
+    fn serialize_bool(self, v: bool) -> Result<Value, Error> {
+        Ok(Value::Bool(v))
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<Value, Error> {
+        Ok(Value::I8(v))
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<Value, Error> {
+        Ok(Value::I16(v))
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<Value, Error> {
+        Ok(Value::I32(v))
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<Value, Error> {
+        Ok(Value::I64(v))
+    }
+
+    fn serialize_i128(self, v: i128) -> Result<Value, Error> {
+        Ok(Value::I128(v))
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<Value, Error> {
+        Ok(Value::U8(v))
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<Value, Error> {
+        Ok(Value::U16(v))
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<Value, Error> {
+        Ok(Value::U32(v))
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<Value, Error> {
+        Ok(Value::U64(v))
+    }
+
+    fn serialize_u128(self, v: u128) -> Result<Value, Error> {
+        Ok(Value::U128(v))
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<Value, Error> {
+        Ok(Value::F32(v))
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<Value, Error> {
+        Ok(Value::F64(v))
+    }
+
+    fn serialize_char(self, v: char) -> Result<Value, Error> {
+        Ok(Value::Char(v))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<Value, Error> {
+        Ok(Value::String(v.to_string()))
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<Value, Error> {
+        Ok(Value::Bytes(v.to_vec()))
+    }
+
+    fn serialize_none(self) -> Result<Value, Error> {
+        Ok(Value::Option(None))
+    }
+
+    fn serialize_some<T: ?Sized + Serialize>(self, value: &T) -> Result<Value, Error> {
+        Ok(Value::Option(Some(Box::new(value.serialize(self)?))))
+    }
+
+    fn serialize_unit(self) -> Result<Value, Error> {
+        Ok(Value::Unit)
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Value, Error> {
+        Ok(Value::Unit)
+    }
+
+    fn serialize_unit_variant(self, _name: &'static str, variant_index: u32, variant: &'static str) -> Result<Value, Error> {
+        Ok(Value::UnitVariant(variant_index, Cow::Borrowed(variant)))
+    }
+
+    fn serialize_newtype_struct<T: ?Sized + Serialize>(self, _name: &'static str, value: &T) -> Result<Value, Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: ?Sized + Serialize>(self, _name: &'static str, variant_index: u32, variant: &'static str, value: &T) -> Result<Value, Error> {
+        Ok(Value::NewtypeVariant(variant_index, Cow::Borrowed(variant), Box::new(value.serialize(self)?)))
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<SeqBuilder, Error> {
+        Ok(SeqBuilder { shape: SeqShape::Seq, elements: Vec::with_capacity(len.unwrap_or(0)) })
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<SeqBuilder, Error> {
+        Ok(SeqBuilder { shape: SeqShape::Tuple, elements: Vec::with_capacity(len) })
+    }
+
+    fn serialize_tuple_struct(self, _name: &'static str, len: usize) -> Result<SeqBuilder, Error> {
+        self.serialize_tuple(len)
+    }
+
+    fn serialize_tuple_variant(self, _name: &'static str, variant_index: u32, variant: &'static str, len: usize) -> Result<SeqBuilder, Error> {
+        Ok(SeqBuilder { shape: SeqShape::Variant(variant_index, variant), elements: Vec::with_capacity(len) })
+    }
+
+    fn serialize_map(self, len: Option<usize>) -> Result<MapBuilder, Error> {
+        Ok(MapBuilder { entries: Vec::with_capacity(len.unwrap_or(0)), key: None })
+    }
+
+    fn serialize_struct(self, _name: &'static str, len: usize) -> Result<StructBuilder, Error> {
+        Ok(StructBuilder { variant: None, fields: Vec::with_capacity(len) })
+    }
+
+    fn serialize_struct_variant(self, _name: &'static str, variant_index: u32, variant: &'static str, len: usize) -> Result<StructBuilder, Error> {
+        Ok(StructBuilder { variant: Some((variant_index, variant)), fields: Vec::with_capacity(len) })
+    }
+}
+
+/// What a [`SeqBuilder`] turns into.
+enum SeqShape {
+    Seq,
+    Tuple,
+    Variant(u32, &'static str),
+}
+
+struct SeqBuilder {
+    shape: SeqShape,
+    elements: Vec<Value>,
+}
+
+impl serde::ser::SerializeSeq for SeqBuilder
+{
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+        self.elements.push(value.serialize(ValueSerializer)?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        Ok(match self.shape {
+            SeqShape::Seq => Value::Seq(self.elements),
+            SeqShape::Tuple => Value::Tuple(self.elements),
+            SeqShape::Variant(index, name) => Value::TupleVariant(index, Cow::Borrowed(name), self.elements),
+        })
+    }
+}
+
+impl serde::ser::SerializeTuple for SeqBuilder
+{
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+        serde::ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        serde::ser::SerializeSeq::end(self)
+    }
+}
+
+impl serde::ser::SerializeTupleStruct for SeqBuilder
+{
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+        serde::ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        serde::ser::SerializeSeq::end(self)
+    }
+}
+
+impl serde::ser::SerializeTupleVariant for SeqBuilder
+{
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+        serde::ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        serde::ser::SerializeSeq::end(self)
+    }
+}
+
+struct MapBuilder {
+    entries: Vec<(Value, Value)>,
+    /// Key waiting for its value.
+    key: Option<Value>,
+}
+
+impl serde::ser::SerializeMap for MapBuilder
+{
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_key<T: ?Sized + Serialize>(&mut self, key: &T) -> Result<(), Error> {
+        self.key = Some(key.serialize(ValueSerializer)?);
+        Ok(())
+    }
+
+    fn serialize_value<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+        let key = self.key.take().ok_or_else(|| Error::new(ErrorKind::Custom, "map value without a key"))?;
+        self.entries.push((key, value.serialize(ValueSerializer)?));
+        Ok(())
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        Ok(Value::Map(self.entries))
+    }
+}
+
+struct StructBuilder {
+    variant: Option<(u32, &'static str)>,
+    fields: Vec<(Cow<'static, str>, Option<Value>)>,
+}
+
+impl serde::ser::SerializeStruct for StructBuilder
+{
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, key: &'static str, value: &T) -> Result<(), Error> {
+        self.fields.push((Cow::Borrowed(key), Some(value.serialize(ValueSerializer)?)));
+        Ok(())
+    }
+
+    fn skip_field(&mut self, key: &'static str) -> Result<(), Error> {
+        self.fields.push((Cow::Borrowed(key), None));
+        Ok(())
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        Ok(match self.variant {
+            Some((index, name)) => Value::StructVariant(index, Cow::Borrowed(name), self.fields),
+            None => Value::Struct(self.fields),
+        })
+    }
+}
+
+impl serde::ser::SerializeStructVariant for StructBuilder
+{
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, key: &'static str, value: &T) -> Result<(), Error> {
+        serde::ser::SerializeStruct::serialize_field(self, key, value)
+    }
+
+    fn skip_field(&mut self, key: &'static str) -> Result<(), Error> {
+        serde::ser::SerializeStruct::skip_field(self, key)
+    }
+
+    fn end(self) -> Result<Value, Error> {
+        serde::ser::SerializeStruct::end(self)
+    }
+}
+
+impl<'de> IntoDeserializer<'de, Error> for Value
+{
+    type Deserializer = Self;
+
+    fn into_deserializer(self) -> Self {
+        self
+    }
+}
+
+/// Hand the elements to `visitor` and check that it took all of them.
+fn visit_seq<'de, V: serde::de::Visitor<'de>>(elements: Vec<Value>, visitor: V) -> Result<V::Value, Error> {
+    let mut seq = serde::de::value::SeqDeserializer::new(elements.into_iter());
+    let value = visitor.visit_seq(&mut seq)?;
+    seq.end()?;
+    Ok(value)
+}
+
+fn visit_map<'de, V: serde::de::Visitor<'de>>(entries: impl Iterator<Item = (Value, Value)>, visitor: V) -> Result<V::Value, Error> {
+    let mut map = serde::de::value::MapDeserializer::new(entries);
+    let value = visitor.visit_map(&mut map)?;
+    map.end()?;
+    Ok(value)
+}
+
+/// The fields of a struct that are there as map entries keyed by field name.
+fn field_entries(fields: Vec<(Cow<'static, str>, Option<Value>)>) -> impl Iterator<Item = (Value, Value)> {
+    fields.into_iter().filter_map(|(key, value)| Some((Value::String(key.into_owned()), value?)))
+}
+
+impl<'de> serde::Deserializer<'de> for Value {
+    type Error = Error;
+
+    fn deserialize_any<V: serde::de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match self {
+            Value::Unit => visitor.visit_unit(),
+            Value::Bool(v) => visitor.visit_bool(v),
+            Value::U8(v) => visitor.visit_u8(v),
+            Value::U16(v) => visitor.visit_u16(v),
+            Value::U32(v) => visitor.visit_u32(v),
+            Value::U64(v) => visitor.visit_u64(v),
+            Value::U128(v) => visitor.visit_u128(v),
+            Value::I8(v) => visitor.visit_i8(v),
+            Value::I16(v) => visitor.visit_i16(v),
+            Value::I32(v) => visitor.visit_i32(v),
+            Value::I64(v) => visitor.visit_i64(v),
+            Value::I128(v) => visitor.visit_i128(v),
+            Value::F32(v) => visitor.visit_f32(v),
+            Value::F64(v) => visitor.visit_f64(v),
+            Value::Char(v) => visitor.visit_char(v),
+            Value::String(v) => visitor.visit_string(v),
+            Value::Bytes(v) => visitor.visit_byte_buf(v),
+            Value::Option(None) => visitor.visit_none(),
+            Value::Option(Some(v)) => visitor.visit_some(*v),
+            Value::Seq(v) | Value::Tuple(v) => visit_seq(v, visitor),
+            Value::Map(v) => visit_map(v.into_iter(), visitor),
+            Value::Struct(v) => visit_map(field_entries(v), visitor),
+            Value::UnitVariant(..) | Value::NewtypeVariant(..) | Value::TupleVariant(..) | Value::StructVariant(..) => visitor.visit_enum(self),
+        }
+    }
+
+    /// Anything but an option or `Unit` is taken to be present.
+    fn deserialize_option<V: serde::de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        match self {
+            Value::Option(None) | Value::Unit => visitor.visit_none(),
+            Value::Option(Some(v)) => visitor.visit_some(*v),
+            other => visitor.visit_some(other),
+        }
+    }
+
+    fn deserialize_newtype_struct<V: serde::de::Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    /// Besides a variant, takes a variant name for a unit variant and a map with a single entry,
+    /// which is how self-describing input spells variants.
+    fn deserialize_enum<V: serde::de::Visitor<'de>>(self, _name: &'static str, _variants: &'static [&'static str], visitor: V) -> Result<V::Value, Self::Error> {
+        match self {
+            Value::UnitVariant(..) | Value::NewtypeVariant(..) | Value::TupleVariant(..) | Value::StructVariant(..) => visitor.visit_enum(self),
+            Value::String(_) | Value::U32(_) => visitor.visit_enum(Enum { variant: self, payload: Value::Unit }),
+            Value::Map(mut entries) if entries.len() == 1 => {
+                let (variant, payload) = entries.pop().unwrap();
+                visitor.visit_enum(Enum { variant, payload })
+            }
+            other => Err(other.invalid_type(&"an enum variant")),
+        }
+    }
+
+    fn deserialize_ignored_any<V: serde::de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_unit()
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct seq tuple tuple_struct map struct identifier
+    }
+}
+
+impl<'de> serde::de::EnumAccess<'de> for Value
+{
+    type Error = Error;
+    type Variant = Value;
+
+    fn variant_seed<V: serde::de::DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, Value), Error> {
+        let (index, payload) = match self {
+            Value::UnitVariant(index, _) => (index, Value::Unit),
+            Value::NewtypeVariant(index, _, payload) => (index, *payload),
+            Value::TupleVariant(index, _, elements) => (index, Value::Tuple(elements)),
+            Value::StructVariant(index, _, fields) => (index, Value::Struct(fields)),
+            other => return Err(other.invalid_type(&"an enum variant")),
+        };
+        Ok((seed.deserialize(index.into_deserializer())?, payload))
+    }
+}
+
+/// A variant that was not written as one of the variants of [`Value`], identified by its name or index.
+struct Enum {
+    variant: Value,
+    payload: Value,
+}
+
+impl<'de> serde::de::EnumAccess<'de> for Enum
+{
+    type Error = Error;
+    type Variant = Value;
+
+    fn variant_seed<V: serde::de::DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, Value), Error> {
+        Ok((seed.deserialize(self.variant)?, self.payload))
+    }
+}
+
+impl<'de> serde::de::VariantAccess<'de> for Value
+{
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<(), Error> {
+        match self {
+            Value::Unit => Ok(()),
+            other => Err(other.invalid_type(&"a unit variant")),
+        }
+    }
+
+    fn newtype_variant_seed<T: serde::de::DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, Error> {
+        seed.deserialize(self)
+    }
+
+    fn tuple_variant<V: serde::de::Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, Error> {
+        match self {
+            Value::Seq(elements) | Value::Tuple(elements) => visit_seq(elements, visitor),
+            other => Err(other.invalid_type(&"a tuple variant")),
+        }
+    }
+
+    fn struct_variant<V: serde::de::Visitor<'de>>(self, _fields: &'static [&'static str], visitor: V) -> Result<V::Value, Error> {
+        match self {
+            Value::Map(entries) => visit_map(entries.into_iter(), visitor),
+            Value::Struct(fields) => visit_map(field_entries(fields), visitor),
+            Value::Seq(elements) | Value::Tuple(elements) => visit_seq(elements, visitor),
+            other => Err(other.invalid_type(&"a struct variant")),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use super::super::{to_bytes, vec, Config, StructEncoding};
+    use std::collections::BTreeMap;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Point(i32, i32);
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    enum Shape {
+        Empty,
+        Circle(u32),
+        Line(Point, Point),
+        Rect { corner: Point, size: (u16, u16), label: Option<String> },
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Drawing {
+        name: String,
+        shapes: Vec<Shape>,
+        origin: (i8, [u8; 3]),
+        tags: BTreeMap<String, u64>,
+    }
+
+    fn drawing() -> Drawing {
+        Drawing {
+            name: "sketch".into(),
+            shapes: vec![
+                Shape::Empty,
+                Shape::Circle(5),
+                Shape::Line(Point(0, 0), Point(-3, 4)),
+                Shape::Rect { corner: Point(1, 2), size: (3, 4), label: Some("box".into()) },
+                Shape::Rect { corner: Point(0, 0), size: (0, 0), label: None },
+            ],
+            origin: (-1, [1, 2, 3]),
+            tags: [("a".to_string(), 1), ("b".to_string(), 2)].into(),
+        }
+    }
+
+    #[test]
+    fn values_encode_like_the_data_they_were_made_from() {
+        let drawing = drawing();
+        let value = to_value(&drawing).unwrap();
+        assert_eq!(to_bytes(&value).unwrap(), to_bytes(&drawing).unwrap());
+        for config in [
+            Config::default().struct_encoding(StructEncoding::Named),
+            Config::default().struct_encoding(StructEncoding::Presence),
+            Config::default().self_describing(true),
+            Config::default().canonical(true),
+        ] {
+            assert_eq!(config.to_bytes(&value).unwrap(), config.to_bytes(&drawing).unwrap(), "{:?}", config);
+        }
+        assert_eq!(from_value::<Drawing>(value).unwrap(), drawing);
+    }
+
+    #[test]
+    fn values_keep_the_shape_of_tuples_structs_and_variants() {
+        assert_eq!(to_value(&(1u8, 2u8)).unwrap(), Value::Tuple(vec![Value::U8(1), Value::U8(2)]));
+        assert_eq!(to_value(&Shape::Empty).unwrap(), Value::UnitVariant(0, "Empty".into()));
+        assert_eq!(to_value(&Shape::Circle(5)).unwrap(), packed_value!(enum(1, "Circle", 5u32)));
+        assert!(matches!(to_value(&Shape::Line(Point(0, 0), Point(1, 1))).unwrap(), Value::TupleVariant(2, _, elements) if elements.len() == 2));
+        let Value::StructVariant(3, name, fields) = to_value(&Shape::Rect { corner: Point(0, 0), size: (0, 0), label: None }).unwrap() else {
+            panic!("not a struct variant");
+        };
+        assert_eq!(name, "Rect");
+        assert_eq!(fields.iter().map(|(key, _)| &**key).collect::<Vec<_>>(), ["corner", "size", "label"]);
+    }
+
+    #[test]
+    fn owned_names_fall_back_to_the_self_describing_spelling() {
+        let config = Config::default().self_describing(true);
+        let value = Value::StructVariant(3, "Rect".to_string().into(), vec![
+            ("corner".to_string().into(), Some(Value::Tuple(vec![Value::I32(1), Value::I32(2)]))),
+            ("size".into(), Some(Value::Tuple(vec![Value::U16(3), Value::U16(4)]))),
+            ("label".into(), Some(Value::Option(None))),
+        ]);
+        let bytes = config.to_bytes(&value).unwrap();
+        let read: Value = config.deserialize_from_bytes(&bytes).unwrap();
+        assert_eq!(from_value::<Shape>(read).unwrap(), Shape::Rect { corner: Point(1, 2), size: (3, 4), label: None });
+        let unit = config.to_bytes(&Value::UnitVariant(0, "Empty".to_string().into())).unwrap();
+        let read: Value = config.deserialize_from_bytes(&unit).unwrap();
+        assert_eq!(from_value::<Shape>(read).unwrap(), Shape::Empty);
+    }
+
+    #[test]
+    fn decoding_into_a_value_needs_self_describing_input() {
+        let drawing = drawing();
+        let config = Config::default().self_describing(true);
+        let value: Value = config.deserialize_from_bytes(&config.to_bytes(&drawing).unwrap()).unwrap();
+        assert_eq!(from_value::<Drawing>(value).unwrap(), drawing);
+
+        let err = super::super::from_bytes::<Value>(&to_bytes(&drawing).unwrap()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Unsupported);
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Sparse {
+        id: u32,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        note: Option<String>,
+        flags: Option<u8>,
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    enum Event {
+        Edit { at: u64, #[serde(default, skip_serializing_if = "Vec::is_empty")] tags: Vec<String> },
+    }
+
+    #[test]
+    fn the_macro_builds_every_shape() {
+        assert_eq!(packed_value!(()), Value::Unit);
+        assert_eq!(packed_value!(None), Value::Option(None));
+        assert_eq!(packed_value!(Some(Some(1u8))), Value::Option(Some(Box::new(Value::Option(Some(Box::new(Value::U8(1))))))));
+        assert_eq!(packed_value!([]), Value::Seq(vec![]));
+        assert_eq!(packed_value!({}), Value::Map(vec![]));
+        assert_eq!(packed_value!(-2), Value::I32(-2));
+        assert_eq!(packed_value!([-2, 3u8]), Value::Seq(vec![Value::I32(-2), Value::U8(3)]));
+        assert_eq!(packed_value!(enum(1, "Circle")), Value::UnitVariant(1, "Circle".into()));
+        assert_eq!(
+            packed_value!(enum(2, "Line", [(), None])),
+            Value::NewtypeVariant(2, "Line".into(), Box::new(Value::Seq(vec![Value::Unit, Value::Option(None)]))),
+        );
+        assert_eq!(
+            packed_value!({ "points" => [[1u8, 2u8], [], Some(-3)], "meta" => { 'k' => {}, "v" => enum(0, "Empty") } }),
+            Value::Map(vec![
+                (Value::String("points".into()), Value::Seq(vec![
+                    Value::Seq(vec![Value::U8(1), Value::U8(2)]),
+                    Value::Seq(vec![]),
+                    Value::Option(Some(Box::new(Value::I32(-3)))),
+                ])),
+                (Value::String("meta".into()), Value::Map(vec![
+                    (Value::Char('k'), Value::Map(vec![])),
+                    (Value::String("v".into()), Value::UnitVariant(0, "Empty".into())),
+                ])),
+            ]),
+        );
+    }
+
+    #[test]
+    fn skipped_fields_keep_their_place_in_named_and_presence_structs() {
+        let sparse = Sparse { id: 7, note: None, flags: Some(1) };
+        let value = to_value(&sparse).unwrap();
+        assert_eq!(value, Value::Struct(vec![
+            ("id".into(), Some(Value::U32(7))),
+            ("note".into(), None),
+            ("flags".into(), Some(packed_value!(Some(1u8)))),
+        ]));
+        let event = Event::Edit { at: 3, tags: vec![] };
+        let event_value = to_value(&event).unwrap();
+        assert_eq!(event_value, Value::StructVariant(0, "Edit".into(), vec![("at".into(), Some(Value::U64(3))), ("tags".into(), None)]));
+
+        for config in [
+            Config::default().struct_encoding(StructEncoding::Named),
+            Config::default().struct_encoding(StructEncoding::Presence),
+        ] {
+            let bytes = config.to_bytes(&sparse).unwrap();
+            assert_eq!(config.to_bytes(&value).unwrap(), bytes, "{:?}", config);
+            let read: Sparse = config.deserialize_from_bytes(&bytes).unwrap();
+            assert_eq!(config.to_bytes(&to_value(&read).unwrap()).unwrap(), bytes, "{:?}", config);
+            assert_eq!(read, sparse);
+
+            let bytes = config.to_bytes(&event).unwrap();
+            assert_eq!(config.to_bytes(&event_value).unwrap(), bytes, "{:?}", config);
+            assert_eq!(config.deserialize_from_bytes::<Event>(&bytes).unwrap(), event);
+        }
+        assert_eq!(from_value::<Sparse>(value).unwrap(), sparse);
+        assert_eq!(from_value::<Event>(event_value).unwrap(), event);
+        assert_eq!(to_bytes(&to_value(&sparse).unwrap()).unwrap_err().kind(), to_bytes(&sparse).unwrap_err().kind());
+    }
+}
@@ -4,7 +4,7 @@
 use serde::{Serialize, Deserialize};
 use ciborium::{from_reader, into_writer};
 
-mod packed;
+use serdes_sandbox::packed;
 
 #[derive(Serialize, Deserialize, Debug)]
 struct Point {